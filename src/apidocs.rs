@@ -13,6 +13,17 @@ pub struct StamJson {
     r#type: String,
 }
 
+#[derive(ToSchema)]
+/// A text selection along with the annotations that are in a particular relation with it
+#[allow(dead_code)]
+pub struct TextSelectionWithAnnotations {
+    /// The text selection in STAM JSON
+    textselection: StamJson,
+
+    /// Annotations in STAM JSON
+    annotations: Vec<StamJson>,
+}
+
 #[derive(ToSchema)]
 /// An API error in JSON
 #[allow(dead_code)]
@@ -21,7 +32,7 @@ pub struct ApiError {
     /// The type of error, this will be "ApiError"
     r#type: String,
 
-    /// The error name (MissingArgument, InternalError, NotFound, CustomNotFound, NotAcceptable, PermissionDenied)
+    /// The error name (MissingArgument, InvalidArgument, InternalError, NotFound, CustomNotFound, NotAcceptable, PermissionDenied, Timeout, Conflict)
     name: String,
 
     /// The error message
@@ -56,3 +67,56 @@ pub struct WebAnnotation {
     /// The identifier
     id: Option<String>,
 }
+
+#[derive(ToSchema)]
+/// A collection of web annotations in JSON-LD, as returned for queries. All annotations are in the first (and only) page. See <https://www.w3.org/TR/annotation-model/#annotation-collection>
+#[allow(dead_code)]
+pub struct WebAnnotationCollection {
+    #[schema(rename = "@context")]
+    /// JSON-LD context
+    context: String,
+
+    /// The type of the RDF resource, this will be "AnnotationCollection"
+    r#type: String,
+
+    /// The total number of annotations in the collection
+    total: usize,
+
+    /// The page of annotations
+    first: WebAnnotationPage,
+}
+
+#[derive(ToSchema)]
+/// A page of web annotations in JSON-LD, see <https://www.w3.org/TR/annotation-model/#annotation-page>
+#[allow(dead_code)]
+pub struct WebAnnotationPage {
+    /// The type of the RDF resource, this will be "AnnotationPage"
+    r#type: String,
+
+    /// The index of the first annotation in this page (relative to the collection)
+    #[schema(rename = "startIndex")]
+    start_index: usize,
+
+    /// The annotations
+    items: Vec<WebAnnotation>,
+}
+
+#[derive(ToSchema)]
+/// The parsed structure of a STAMQL query, as returned by the explain endpoint. This schema is a rough skeleton, the `query` and `error` fields are only described informally.
+#[allow(dead_code)]
+pub struct QueryExplanation {
+    /// Whether the query is syntactically valid
+    valid: bool,
+
+    /// Whether the query (including all subqueries) is read-only, i.e. does not add or delete anything (only if valid)
+    readonly: Option<bool>,
+
+    /// All variable names declared in the query, without `?` prefix (only if valid)
+    variables: Option<Vec<String>>,
+
+    /// The query, with keys `type`, `resulttype`, `variable`, `optional`, `attributes`, `constraints` (each with a `keyword`, `statement` and `attributes`) and `subqueries` (recursive) (only if valid)
+    query: Option<serde_json::Value>,
+
+    /// The syntax error, with keys `name`, `message` and `position`. The position holds offsets in various units (`unicode`, `bytes`, `utf16`) and a 1-indexed `line` and `column`, it is `null` if it can not be determined (only if invalid)
+    error: Option<serde_json::Value>,
+}
@@ -0,0 +1,385 @@
+use crate::common::ApiError;
+use crate::multistore::{check_files, check_store_json};
+use serde::Serialize;
+use stam::{AnnotationStore, AssociatedFile, Configurable, DataFormat, ToJson};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Makes the names of temporary files unique within this process
+static TMPFILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+/// A backup of an earlier version of a store
+pub struct Backup {
+    /// Identifier of the backup, this is the time it was made in milliseconds since the unix epoch
+    pub backup: u64,
+
+    /// Size of the backup in bytes
+    pub size: u64,
+
+    /// Whether this is a copy of unsaved changes, kept on a conflict or reload. These are not removed automatically.
+    pub kept: bool,
+}
+
+/// Saves a store to its file and keeps backups of the versions it replaces.
+/// Backups are kept alongside the store in the base directory, as `{store_id}.{backup}.bak`,
+/// copies of unsaved changes as `{store_id}.{backup}.kept.bak`.
+pub struct Backups {
+    /// The store file
+    storefile: PathBuf,
+    /// The store file without any extension, backups are named after it
+    base: PathBuf,
+    /// Number of backups to keep
+    keep: usize,
+    /// The base directory, a restored store may not refer to any files outside it
+    basedir: PathBuf,
+}
+
+impl Backups {
+    pub fn new(
+        storefile: impl Into<PathBuf>,
+        base: impl Into<PathBuf>,
+        keep: usize,
+        basedir: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            storefile: storefile.into(),
+            base: base.into(),
+            keep,
+            basedir: basedir.into(),
+        }
+    }
+
+    fn backup_filename(&self, backup: u64, kept: bool) -> PathBuf {
+        if kept {
+            self.base.with_extension(format!("{}.kept.bak", backup))
+        } else {
+            self.base.with_extension(format!("{}.bak", backup))
+        }
+    }
+
+    /// Returns the file of an existing backup
+    fn find(&self, backup: u64) -> Option<PathBuf> {
+        [false, true]
+            .into_iter()
+            .map(|kept| self.backup_filename(backup, kept))
+            .find(|filename| filename.exists())
+    }
+
+    /// Returns all backups, oldest first
+    pub fn list(&self) -> Result<Vec<Backup>, ApiError> {
+        let dir = self.base.parent().unwrap_or(Path::new("."));
+        let prefix = match self.base.file_name().and_then(|s| s.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return Ok(Vec::new()),
+        };
+        let mut backups = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .map_err(|_| ApiError::InternalError("Unable to read base directory"))?
+        {
+            let entry =
+                entry.map_err(|_| ApiError::InternalError("Unable to read base directory"))?;
+            if let Some(filename) = entry.file_name().to_str() {
+                if let Some(backup) = filename
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|s| s.strip_suffix(".bak"))
+                {
+                    let (backup, kept) = match backup.strip_suffix(".kept") {
+                        Some(backup) => (backup, true),
+                        None => (backup, false),
+                    };
+                    if let Ok(backup) = backup.parse::<u64>() {
+                        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                        backups.push(Backup { backup, size, kept });
+                    }
+                }
+            }
+        }
+        backups.sort_by_key(|backup| backup.backup);
+        Ok(backups)
+    }
+
+    /// Writes the store to its file, see [`Self::replace()`]
+    pub fn save(&self, store: &AnnotationStore) -> Result<(), ApiError> {
+        match serialize(store)? {
+            Some(data) => self.replace(&data),
+            None => {
+                //formats that span multiple files (STAM CSV) can not be replaced atomically, nor backed up
+                store.save().map_err(ApiError::StamError)
+            }
+        }
+    }
+
+    /// Writes serialized data to the store file. The data is first written to a temporary file which then replaces the store file,
+    /// so an interrupted save never leaves a corrupted store behind. The version that is replaced is kept as a backup,
+    /// and backups beyond the configured number are removed.
+    pub fn replace(&self, data: &[u8]) -> Result<(), ApiError> {
+        let (tmpfilename, mut file) = self.create_tmpfile()?;
+        let result = file
+            .write_all(data)
+            .and_then(|_| file.sync_all())
+            .map_err(|_| ApiError::InternalError("Unable to write store"))
+            .and_then(|_| {
+                if self.keep > 0 && self.storefile.exists() {
+                    let filename = self.backup_filename(self.next_backup(), false);
+                    //a hard link costs nothing, the rename below detaches it from the new version
+                    if std::fs::hard_link(&self.storefile, &filename).is_err() {
+                        std::fs::copy(&self.storefile, &filename).map_err(|_| {
+                            ApiError::InternalError("Unable to make a backup of the store")
+                        })?;
+                    }
+                }
+                std::fs::rename(&tmpfilename, &self.storefile)
+                    .map_err(|_| ApiError::InternalError("Unable to replace store"))
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmpfilename);
+        }
+        result?;
+        self.prune()
+    }
+
+    /// Creates a new temporary file next to the store file. Its name is unique, so concurrent saves never write to the same file.
+    fn create_tmpfile(&self) -> Result<(PathBuf, File), ApiError> {
+        loop {
+            let tmpfilename = self.base.with_extension(format!(
+                "{}.{}.tmp",
+                std::process::id(),
+                TMPFILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match File::create_new(&tmpfilename) {
+                Ok(file) => return Ok((tmpfilename, file)),
+                //left behind by an earlier process with the same process id
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(_) => return Err(ApiError::InternalError("Unable to write store")),
+            }
+        }
+    }
+
+    /// Writes the store as it is in memory to a new backup, without touching the store file. Returns the identifier of the backup.
+    /// Unlike the backups made on save, these are never pruned, as the changes are not saved anywhere else.
+    pub fn keep_copy(&self, store: &AnnotationStore) -> Result<u64, ApiError> {
+        let data = serialize(store)?.ok_or(ApiError::InternalError(
+            "Unable to make a backup of a store in this format",
+        ))?;
+        let backup = self.next_backup();
+        std::fs::write(self.backup_filename(backup, true), data)
+            .map_err(|_| ApiError::InternalError("Unable to make a backup of the store"))?;
+        Ok(backup)
+    }
+
+    /// Moves all backups to another store (when it is renamed)
+    pub fn move_to(&self, other: &Backups) -> Result<(), ApiError> {
+        for backup in self.list()? {
+            std::fs::rename(
+                self.backup_filename(backup.backup, backup.kept),
+                other.backup_filename(backup.backup, backup.kept),
+            )
+            .map_err(|_| ApiError::InternalError("Unable to move backup"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns an identifier for a new backup
+    fn next_backup(&self) -> u64 {
+        let mut backup = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        while self.find(backup).is_some() {
+            backup += 1;
+        }
+        backup
+    }
+
+    /// Removes the oldest backups beyond the configured number, kept copies of unsaved changes do not count
+    fn prune(&self) -> Result<(), ApiError> {
+        let backups: Vec<Backup> = self
+            .list()?
+            .into_iter()
+            .filter(|backup| !backup.kept)
+            .collect();
+        if backups.len() > self.keep {
+            for backup in &backups[..backups.len() - self.keep] {
+                if std::fs::remove_file(self.backup_filename(backup.backup, false)).is_err() {
+                    warn!("Unable to remove backup {}", backup.backup);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a backup of the store. The result takes the place of `store`, so it gets the same filename and configuration.
+    pub fn load(&self, backup: u64, store: &AnnotationStore) -> Result<AnnotationStore, ApiError> {
+        let filename = self
+            .find(backup)
+            .ok_or(ApiError::NotFound("No such backup"))?;
+        let data = std::fs::read(filename)
+            .map_err(|_| ApiError::InternalError("Unable to read backup"))?;
+        let mut restored: AnnotationStore = match store.config().dataformat() {
+            DataFormat::CBOR => minicbor::decode(&data)
+                .map_err(|_| ApiError::InternalError("Unable to restore the store"))?,
+            _ => {
+                let data = String::from_utf8(data)
+                    .map_err(|_| ApiError::InternalError("Backup is not valid UTF-8"))?;
+                //stand-off files are included relative to the store file, not to the backup
+                let mut config = store.config().clone();
+                if config.workdir().is_none() {
+                    if let Some(dir) = self.storefile.parent().and_then(|dir| dir.to_str()) {
+                        config = config.with_workdir(dir.to_string());
+                    }
+                }
+                check_store_json(&self.basedir, &data, config.workdir())?;
+                AnnotationStore::from_str(&data, config)?
+            }
+        };
+        check_files(&self.basedir, &restored)?;
+        if let Some(filename) = store.filename() {
+            restored.set_filename(filename);
+        }
+        Ok(restored)
+    }
+}
+
+/// Serializes the store like [`serialize()`], but under another identifier and filename, for copies of the store.
+/// The copy is made by a round-trip through STAM CBOR, which holds the entire store.
+pub fn serialize_as(
+    store: &AnnotationStore,
+    id: &str,
+    filename: &Path,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    if !matches!(
+        store.config().dataformat(),
+        DataFormat::Json { .. } | DataFormat::CBOR
+    ) {
+        return Ok(None);
+    }
+    let filename = filename.to_str().ok_or(ApiError::InvalidArgument(
+        "Invalid filename (invalid unicode)",
+    ))?;
+    let copy: AnnotationStore = minicbor::to_vec(store)
+        .ok()
+        .and_then(|data| minicbor::decode(&data).ok())
+        .ok_or(ApiError::InternalError("Unable to serialize the store"))?;
+    let mut copy = copy.with_id(id);
+    copy.set_filename(filename);
+    match store.config().dataformat() {
+        DataFormat::Json { .. } => {
+            //STAM writes pending changes to stand-off files while serializing to JSON, the copy has none pending,
+            //so they are written for the store itself first
+            store.to_json_string(store.config())?;
+            Ok(Some(copy.to_json_string(store.config())?.into_bytes()))
+        }
+        _ => minicbor::to_vec(copy)
+            .map(Some)
+            .map_err(|_| ApiError::InternalError("Unable to serialize the store")),
+    }
+}
+
+/// Serializes the store in the format of its file, returns `None` for formats that can not be written to a single file
+fn serialize(store: &AnnotationStore) -> Result<Option<Vec<u8>>, ApiError> {
+    match store.config().dataformat() {
+        DataFormat::Json { .. } => Ok(Some(store.to_json_string(store.config())?.into_bytes())),
+        DataFormat::CBOR => minicbor::to_vec(store)
+            .map(Some)
+            .map_err(|_| ApiError::InternalError("Unable to serialize the store")),
+        #[allow(unreachable_patterns)]
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stam::{Config, TextResourceBuilder};
+    use std::fs;
+
+    #[test]
+    fn concurrent_replace() {
+        let dir = std::env::temp_dir().join(format!("stamd-test-replace-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let backups = Backups::new(dir.join("t.store.stam.json"), dir.join("t"), 2, &dir);
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let backups = &backups;
+                scope.spawn(move || {
+                    for j in 0..20 {
+                        backups.replace(format!("{}-{}", i, j).as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        let data = fs::read_to_string(dir.join("t.store.stam.json")).unwrap();
+        assert!(data.ends_with("-19"));
+        for entry in fs::read_dir(&dir).unwrap() {
+            let filename = entry.unwrap().file_name();
+            assert!(!filename.to_string_lossy().ends_with(".tmp"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn kept_copies() {
+        let dir = std::env::temp_dir().join(format!("stamd-test-kept-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        //no backups are made on save, but copies of unsaved changes are still kept
+        let backups = Backups::new(dir.join("t.store.stam.json"), dir.join("t"), 0, &dir);
+        backups.replace(b"1").unwrap();
+        let store = AnnotationStore::new(Config::default()).with_id("t");
+        let kept = backups.keep_copy(&store).unwrap();
+        backups.replace(b"2").unwrap();
+        backups.replace(b"3").unwrap();
+        let list = backups.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].backup, kept);
+        assert!(list[0].kept);
+        assert_eq!(backups.load(kept, &store).unwrap().id(), Some("t"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serialize_copy() {
+        for (dataformat, extension) in [
+            (DataFormat::Json { compact: true }, "json"),
+            (DataFormat::CBOR, "cbor"),
+        ] {
+            let mut store =
+                AnnotationStore::new(Config::default().with_dataformat(dataformat)).with_id("t");
+            store.set_filename(&format!("/base/t.store.stam.{}", extension));
+            store
+                .add_resource(
+                    TextResourceBuilder::new()
+                        .with_id("t")
+                        .with_text("Hello world"),
+                )
+                .unwrap();
+            let filename = format!("/base/sub/copy.store.stam.{}", extension);
+            let data = serialize_as(&store, "copy", Path::new(&filename))
+                .unwrap()
+                .unwrap();
+            let copy: AnnotationStore = match dataformat {
+                DataFormat::CBOR => {
+                    let copy: AnnotationStore = minicbor::decode(&data).unwrap();
+                    assert_eq!(copy.filename(), Some(filename.as_str()));
+                    copy
+                }
+                _ => AnnotationStore::from_str(
+                    std::str::from_utf8(&data).unwrap(),
+                    Config::default(),
+                )
+                .unwrap(),
+            };
+            assert_eq!(copy.id(), Some("copy"));
+            assert!(copy.resource("t").is_some());
+            //the store itself is unchanged
+            assert_eq!(store.id(), Some("t"));
+        }
+    }
+}
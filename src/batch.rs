@@ -0,0 +1,148 @@
+use crate::budget::QueryBudget;
+use crate::common::{parse_query, ApiError, ApiResponse};
+use crate::multistore::StorePool;
+use crate::offsets::OffsetUnit;
+use crate::CONTENT_TYPE_JSON;
+use crate::{parse_relation, query_results, textselection_response, ResultOptions};
+use serde::Deserialize;
+use serde_json::value::Value;
+use stam::{AnnotationStore, FindText, Text};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// A single request in a batch, the `type` property determines what is requested
+pub enum BatchRequest {
+    /// An annotation, returned in STAM JSON
+    Annotation {
+        /// The identifier of the annotation
+        id: String,
+    },
+    /// A text selection in a resource, returned in the same way as by `/{store_id}/resources/{resource_id}/{begin}/{end}`
+    Resource {
+        /// The identifier of the resource
+        id: String,
+        /// The begin offset, see `/{store_id}/resources/{resource_id}/{begin}/{end}`
+        begin: Cursor,
+        /// The non-inclusive end offset, see `/{store_id}/resources/{resource_id}/{begin}/{end}`
+        end: Cursor,
+        /// The unit in which `begin` and `end` are expressed (`unicode`, `bytes`, `utf16`, `line`)
+        #[serde(default)]
+        unit: Option<String>,
+        /// Also return the annotations that are in this relation with the text selection (`overlaps`, `embeds`, `embedded`, `equals`)
+        #[serde(default)]
+        relation: Option<String>,
+    },
+    /// A read-only query in STAMQL, the results are returned in the same way as by `/{store_id}` with JSON output
+    Query {
+        /// The query in STAMQL
+        query: String,
+        /// Only return this variable
+        #[serde(default, rename = "use")]
+        use_variable: Option<String>,
+    },
+}
+
+#[derive(Deserialize, ToSchema, Debug)]
+#[serde(untagged)]
+/// An offset, either as a number or as a string (required for `-0` and `line:column`)
+pub enum Cursor {
+    Number(i64),
+    String(String),
+}
+
+impl Cursor {
+    fn as_string(&self) -> String {
+        match self {
+            Self::Number(n) => n.to_string(),
+            Self::String(s) => s.clone(),
+        }
+    }
+}
+
+/// Executes all requests of a batch under a single read lock on the store, so all results reflect the same state of the store.
+/// Returns one result per request, in the same order. A failing request does not affect the others, its result is the error.
+pub fn execute(
+    store_id: &str,
+    requests: Vec<BatchRequest>,
+    storepool: &StorePool,
+    budget: &QueryBudget,
+) -> Result<ApiResponse, ApiError> {
+    storepool.map(store_id, |store| {
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            budget.check()?;
+            let result = match execute_request(store_id, request, store, storepool, budget) {
+                //a timeout aborts the whole batch rather than just this request
+                Err(err @ ApiError::Timeout(_)) => return Err(err),
+                Err(err) => serde_json::to_value(&err)
+                    .map_err(|_| ApiError::InternalError("Unable to serialize error"))?,
+                Ok(value) => value,
+            };
+            results.push(result);
+        }
+        Ok(ApiResponse::JsonList(results))
+    })
+}
+
+fn execute_request(
+    store_id: &str,
+    request: BatchRequest,
+    store: &AnnotationStore,
+    storepool: &StorePool,
+    budget: &QueryBudget,
+) -> Result<Value, ApiError> {
+    match request {
+        BatchRequest::Annotation { id } => match store.annotation(id.as_str()) {
+            None => Err(ApiError::NotFound("No such annotation")),
+            Some(annotation) => Ok(annotation.as_ref().to_json_value(store)?),
+        },
+        BatchRequest::Resource {
+            id,
+            begin,
+            end,
+            unit,
+            relation,
+        } => {
+            let unit: OffsetUnit = unit
+                .map(|unit| unit.as_str().try_into())
+                .transpose()?
+                .unwrap_or(OffsetUnit::Unicode);
+            let operator = relation
+                .map(|relation| parse_relation(&relation))
+                .transpose()?;
+            match store.resource(id.as_str()) {
+                None => Err(ApiError::NotFound("No such resource")),
+                Some(resource) => {
+                    let offset =
+                        unit.to_offset(resource.text(), &begin.as_string(), &end.as_string())?;
+                    let textselection = resource.textselection(&offset)?;
+                    textselection_response(store, &textselection, operator, Ok(CONTENT_TYPE_JSON))?
+                        .into_json_value()
+                }
+            }
+        }
+        BatchRequest::Query {
+            query,
+            use_variable,
+        } => {
+            let (query, _) = parse_query(&query)?;
+            if !query.querytype().readonly() {
+                return Err(ApiError::PermissionDenied(
+                    "Only read-only queries are allowed in a batch",
+                ));
+            }
+            let queryiter = store.query(query)?;
+            let options = ResultOptions::new(use_variable.as_deref(), None, None, None)?;
+            query_results(
+                queryiter,
+                CONTENT_TYPE_JSON,
+                options,
+                budget,
+                store_id,
+                storepool,
+            )?
+            .into_json_value()
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use crate::common::ApiError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The time budget of a single query. Long running queries check it between results so they
+/// can be aborted, releasing the store lock, when the budget is exhausted or the client has disconnected.
+#[derive(Clone, Debug)]
+pub struct QueryBudget {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl QueryBudget {
+    /// Starts a new budget, `None` means the query may run indefinitely (but can still be cancelled)
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns an error if the query should be aborted
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(ApiError::Timeout("Query was cancelled"))
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Err(ApiError::Timeout("Query exceeded the time limit"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns a guard that cancels the query when it is dropped. Keep it in the request handler,
+    /// which is dropped when the client disconnects.
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.cancelled.clone())
+    }
+}
+
+/// Cancels a query when dropped, see [`QueryBudget::cancel_on_drop()`]
+pub struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::common::{ApiError, ApiResponse};
+use crate::tabular::Column;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Identifies a query result: the query on a store and everything that determines how its results are returned
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub store_id: String,
+    pub querystring: String,
+    pub use_variable: Option<String>,
+    pub content_type: &'static str,
+    pub columns: Option<Vec<Column>>,
+    pub delimiter: String,
+    pub vardelimiter: String,
+}
+
+/// Cache statistics
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CacheMetrics {
+    /// Maximum number of entries
+    capacity: usize,
+    /// Current number of entries
+    entries: usize,
+    /// Maximum size of all entries together, in bytes
+    max_bytes: usize,
+    /// Current size of all entries together, in bytes
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    /// Entries removed to make room for new ones
+    evictions: u64,
+    /// Entries removed because their store changed
+    invalidations: u64,
+}
+
+struct CacheEntry {
+    response: ApiResponse,
+    /// Approximate size of the response in bytes
+    size: usize,
+    last_used: u64,
+}
+
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Total size of all entries
+    bytes: usize,
+    /// Logical clock to keep track of the least recently used entry
+    clock: u64,
+    metrics: CacheMetrics,
+}
+
+/// A least-recently-used cache of responses to read-only queries
+pub struct QueryCache {
+    capacity: usize,
+    max_bytes: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl QueryCache {
+    /// Creates a cache holding at most `capacity` responses, taking up at most `max_bytes` together.
+    /// A capacity or size of 0 disables caching.
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            capacity,
+            max_bytes,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                bytes: 0,
+                clock: 0,
+                metrics: CacheMetrics {
+                    capacity,
+                    max_bytes,
+                    ..CacheMetrics::default()
+                },
+            }),
+        }
+    }
+
+    fn disabled(&self) -> bool {
+        self.capacity == 0 || self.max_bytes == 0
+    }
+
+    /// Returns a cached response, if any
+    pub fn get(&self, key: &CacheKey) -> Option<ApiResponse> {
+        if self.disabled() {
+            return None;
+        }
+        let mut inner = self.inner.lock().ok()?;
+        inner.clock += 1;
+        let clock = inner.clock;
+        if let Some(entry) = inner.entries.get_mut(key) {
+            entry.last_used = clock;
+            let response = entry.response.clone();
+            inner.metrics.hits += 1;
+            Some(response)
+        } else {
+            inner.metrics.misses += 1;
+            None
+        }
+    }
+
+    /// Adds a response to the cache, evicting the least recently used ones if the cache is full.
+    /// Responses that are larger than the entire cache are not added.
+    pub fn insert(&self, key: CacheKey, response: ApiResponse) {
+        if self.disabled() {
+            return;
+        }
+        let size = response_size(&key, &response);
+        if size > self.max_bytes {
+            return;
+        }
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(entry) = inner.entries.remove(&key) {
+                inner.bytes -= entry.size;
+            }
+            while inner.entries.len() >= self.capacity || inner.bytes + size > self.max_bytes {
+                let lru = inner
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(entry) = lru.and_then(|lru| inner.entries.remove(&lru)) {
+                    inner.bytes -= entry.size;
+                    inner.metrics.evictions += 1;
+                } else {
+                    break;
+                }
+            }
+            inner.clock += 1;
+            let last_used = inner.clock;
+            inner.bytes += size;
+            inner.entries.insert(
+                key,
+                CacheEntry {
+                    response,
+                    size,
+                    last_used,
+                },
+            );
+        }
+    }
+
+    /// Removes all cached responses for a store, must be called whenever the store changes
+    pub fn invalidate(&self, store_id: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let before = inner.entries.len();
+            let mut removed_bytes = 0;
+            inner.entries.retain(|key, entry| {
+                if key.store_id == store_id {
+                    removed_bytes += entry.size;
+                    false
+                } else {
+                    true
+                }
+            });
+            inner.bytes -= removed_bytes;
+            inner.metrics.invalidations += (before - inner.entries.len()) as u64;
+        }
+    }
+
+    /// Returns the cache statistics
+    pub fn metrics(&self) -> Result<CacheMetrics, ApiError> {
+        let inner = self
+            .inner
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?;
+        Ok(CacheMetrics {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            ..inner.metrics.clone()
+        })
+    }
+}
+
+/// Approximates the memory taken by a cache entry by the size of its key and its serialized response
+fn response_size(key: &CacheKey, response: &ApiResponse) -> usize {
+    let keysize = key.store_id.len()
+        + key.querystring.len()
+        + key.use_variable.as_ref().map(|s| s.len()).unwrap_or(0)
+        + key.delimiter.len()
+        + key.vardelimiter.len();
+    let responsesize = match response {
+        ApiResponse::Text(s)
+        | ApiResponse::Html(s)
+        | ApiResponse::RawJson(s)
+        | ApiResponse::RawJsonLd(s)
+        | ApiResponse::Tsv(s)
+        | ApiResponse::Csv(s) => s.len(),
+        ApiResponse::JsonList(data) => json_size(data),
+        ApiResponse::JsonMap(data) => json_size(data),
+        ApiResponse::Json(data) => json_size(data),
+        ApiResponse::Created()
+        | ApiResponse::Deleted()
+        | ApiResponse::QueryUI(_)
+        | ApiResponse::Asset(..) => 0,
+    };
+    keysize + responsesize
+}
+
+/// Returns the length of the JSON serialisation of a value, without holding it in memory
+fn json_size(value: &impl Serialize) -> usize {
+    struct Counter(usize);
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut counter = Counter(0);
+    //serializing plain JSON values to a writer that never fails can't fail either
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(store_id: &str, querystring: &str) -> CacheKey {
+        CacheKey {
+            store_id: store_id.to_string(),
+            querystring: querystring.to_string(),
+            use_variable: None,
+            content_type: "text/plain",
+            columns: None,
+            delimiter: String::new(),
+            vardelimiter: String::new(),
+        }
+    }
+
+    /// A response that, along with its key (of two bytes), takes up `size` bytes
+    fn response(size: usize) -> ApiResponse {
+        ApiResponse::Text("x".repeat(size - 2))
+    }
+
+    #[test]
+    fn bounded_by_entries() {
+        let cache = QueryCache::new(2, 1000);
+        cache.insert(key("s", "1"), response(10));
+        cache.insert(key("s", "2"), response(10));
+        assert!(cache.get(&key("s", "1")).is_some());
+        //evicts 2, the least recently used
+        cache.insert(key("s", "3"), response(10));
+        assert!(cache.get(&key("s", "1")).is_some());
+        assert!(cache.get(&key("s", "2")).is_none());
+        assert!(cache.get(&key("s", "3")).is_some());
+        let metrics = cache.metrics().unwrap();
+        assert_eq!(
+            (metrics.entries, metrics.bytes, metrics.evictions),
+            (2, 20, 1)
+        );
+    }
+
+    #[test]
+    fn bounded_by_bytes() {
+        let cache = QueryCache::new(100, 100);
+        cache.insert(key("s", "1"), response(40));
+        cache.insert(key("s", "2"), response(40));
+        //evicts 1
+        cache.insert(key("s", "3"), response(30));
+        assert!(cache.get(&key("s", "1")).is_none());
+        assert_eq!(cache.metrics().unwrap().bytes, 70);
+        //replacing an entry does not count it twice
+        cache.insert(key("s", "3"), response(60));
+        assert!(cache.get(&key("s", "2")).is_some());
+        assert_eq!(cache.metrics().unwrap().bytes, 100);
+        //larger than the entire cache, not cached and nothing is evicted
+        cache.insert(key("s", "4"), response(101));
+        assert!(cache.get(&key("s", "4")).is_none());
+        assert_eq!(cache.metrics().unwrap().entries, 2);
+        //evicts everything
+        cache.insert(key("s", "5"), response(100));
+        let metrics = cache.metrics().unwrap();
+        assert_eq!(
+            (metrics.entries, metrics.bytes, metrics.evictions),
+            (1, 100, 3)
+        );
+    }
+
+    #[test]
+    fn invalidation() {
+        let cache = QueryCache::new(10, 1000);
+        cache.insert(key("s", "1"), response(10));
+        cache.insert(key("t", "1"), response(20));
+        cache.insert(
+            key("s", "2"),
+            ApiResponse::JsonList(vec![serde_json::json!({"a": 1})]),
+        );
+        assert_eq!(cache.metrics().unwrap().bytes, 10 + 20 + 2 + 9);
+        cache.invalidate("s");
+        assert!(cache.get(&key("t", "1")).is_some());
+        let metrics = cache.metrics().unwrap();
+        assert_eq!(
+            (metrics.entries, metrics.bytes, metrics.invalidations),
+            (1, 20, 2)
+        );
+    }
+}
@@ -9,9 +9,10 @@ use serde_json::value::Value;
 use stam::StamError;
 use std::collections::BTreeMap;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ApiResponse {
     Created(),
+    Deleted(),
     Text(String),
     Html(String),
     RawJson(String),
@@ -19,13 +20,21 @@ pub enum ApiResponse {
     RawJsonLd(String),
     JsonList(Vec<Value>),
     JsonMap(Vec<BTreeMap<String, Value>>),
+    Json(Value),
+    /// Tab Separated Values
+    Tsv(String),
+    /// Comma Separated Values
+    Csv(String),
     QueryUI(Vec<String>), //takes a list of store IDs
+    /// Embedded static asset, takes a content type and the content
+    Asset(&'static str, &'static str),
 }
 
 impl IntoResponse for ApiResponse {
     fn into_response(self) -> Response {
         match self {
             Self::Created() => (StatusCode::CREATED, "created").into_response(),
+            Self::Deleted() => (StatusCode::OK, "deleted").into_response(),
             Self::Text(s) => (StatusCode::OK, s).into_response(),
             Self::Html(s) => (StatusCode::OK, Html(s)).into_response(),
             Self::RawJsonLd(data) => (
@@ -52,43 +61,134 @@ impl IntoResponse for ApiResponse {
                 .into_response(),
             Self::JsonList(data) => (StatusCode::OK, Json(data)).into_response(),
             Self::JsonMap(data) => (StatusCode::OK, Json(data)).into_response(),
+            Self::Json(data) => (StatusCode::OK, Json(data)).into_response(),
+            Self::Tsv(data) => (
+                StatusCode::OK,
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/tab-separated-values; charset=utf-8"),
+                )],
+                data,
+            )
+                .into_response(),
+            Self::Csv(data) => (
+                StatusCode::OK,
+                [(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/csv; charset=utf-8"),
+                )],
+                data,
+            )
+                .into_response(),
             Self::QueryUI(store_ids) => {
-                let options: Vec<_> = store_ids
-                    .into_iter()
-                    .map(|s| format!("<option value=\"{}\">{}</option>", s, s))
-                    .collect();
-                let html: String = format!(
-                    "<html>
-<head>
-    <meta content=\"text/html; charset=utf8\" http-equiv=\"content-type\">
-    <title>stamd</title>
-</head>
-<body>
-<h1>stamd</h1>
-<p>See <a href=\"/swagger-ui\">OpenAPI specification</a></p>
-<hr/>
-<form method=\"post\" action=\"/query\">
-<label>Store:</label> <select name=\"store\">{}</select><br/>
-<label>Query (<a href=\"https://github.com/annotation/stam/tree/master/extensions/stam-query\">STAMQL</a>):</label><br/><textarea name=\"query\" style=\"width: 60%; min-height: 360px;\" spellcheck=\"false\"></textarea><br/>
-<input type=\"submit\" />
-</form>
-</body></html>",
-                    options.join("")
-                );
-                (StatusCode::OK, Html(html)).into_response()
+                (StatusCode::OK, Html(crate::ui::index(&store_ids))).into_response()
             }
+            Self::Asset(content_type, data) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+                data,
+            )
+                .into_response(),
         }
     }
 }
 
+impl ApiResponse {
+    /// Converts a JSON (or plain text) response to a JSON value, so it can be embedded in a larger response
+    pub fn into_json_value(self) -> Result<Value, ApiError> {
+        match self {
+            Self::Json(value) => Ok(value),
+            Self::JsonList(values) => Ok(Value::Array(values)),
+            Self::JsonMap(maps) => serde_json::to_value(maps)
+                .map_err(|_| ApiError::InternalError("Unable to serialize results")),
+            Self::RawJson(data) | Self::RawJsonLd(data) => serde_json::from_str(&data)
+                .map_err(|_| ApiError::InternalError("Unable to parse JSON")),
+            Self::Text(text) => Ok(Value::String(text)),
+            _ => Err(ApiError::NotAcceptable(
+                "Response can not be represented in JSON",
+            )),
+        }
+    }
+}
+
+/// Escapes a string for inclusion in HTML
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Quotes a string for safe use as a string literal in a STAMQL query
+pub fn quote_stamql(s: &str) -> Result<String, ApiError> {
+    //STAMQL does not unescape strings, so quotes and backslashes can not be expressed reliably
+    if s.contains('"') || s.contains('\\') {
+        Err(ApiError::InvalidArgument(
+            "Identifiers or values containing quotes or backslashes can not be used in queries",
+        ))
+    } else {
+        Ok(format!("\"{}\"", s))
+    }
+}
+
+/// Parses a query. Use this rather than [`stam::Query::parse()`] directly, which panics on some invalid queries (see [`incomplete_keyword()`]).
+pub fn parse_query(querystring: &str) -> Result<(stam::Query<'_>, &str), StamError> {
+    if let Some((_, keyword)) = incomplete_keyword(querystring) {
+        return Err(StamError::QuerySyntaxError(
+            format!("Expected a result type after {}", keyword),
+            "parse_query",
+        ));
+    }
+    stam::Query::parse(querystring)
+}
+
+/// The parser panics rather than returns an error if a query keyword is not followed by anything, or by a multi-byte character,
+/// which is common for queries that are still being typed. Returns the byte offset after such a keyword, and the keyword.
+pub fn incomplete_keyword(querystring: &str) -> Option<(usize, &'static str)> {
+    let querystring = querystring.trim_end();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut prev: Option<char> = None;
+    for (bytepos, c) in querystring.char_indices() {
+        if !quoted && prev.is_none_or(|prev| !prev.is_alphanumeric()) {
+            for keyword in ["SELECT", "ADD", "DELETE"] {
+                if querystring[bytepos..].starts_with(keyword) {
+                    let end = bytepos + keyword.len();
+                    if querystring[end..]
+                        .chars()
+                        .next()
+                        .is_none_or(|c| !c.is_ascii())
+                    {
+                        return Some((end, keyword));
+                    }
+                }
+            }
+        }
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        }
+        prev = Some(c);
+    }
+    None
+}
+
 #[derive(Debug)]
 pub enum ApiError {
     MissingArgument(&'static str),
+    InvalidArgument(&'static str),
     InternalError(&'static str),
     NotFound(&'static str),
     CustomNotFound(String),
     NotAcceptable(&'static str),
     PermissionDenied(&'static str),
+    /// A query was aborted because it took too long (or the client disconnected)
+    Timeout(&'static str),
+    /// The store was changed on disk as well as in memory
+    Conflict(&'static str),
     StamError(StamError),
 }
 
@@ -107,6 +207,10 @@ impl Serialize for ApiError {
                     state.serialize_field("name", "MissingArgument")?;
                     state.serialize_field("message", s)?;
                 }
+                Self::InvalidArgument(s) => {
+                    state.serialize_field("name", "InvalidArgument")?;
+                    state.serialize_field("message", s)?;
+                }
                 Self::NotFound(s) => {
                     state.serialize_field("name", "NotFound")?;
                     state.serialize_field("message", s)?;
@@ -127,6 +231,14 @@ impl Serialize for ApiError {
                     state.serialize_field("name", "InternalError")?;
                     state.serialize_field("message", s)?;
                 }
+                Self::Timeout(s) => {
+                    state.serialize_field("name", "Timeout")?;
+                    state.serialize_field("message", s)?;
+                }
+                Self::Conflict(s) => {
+                    state.serialize_field("name", "Conflict")?;
+                    state.serialize_field("message", s)?;
+                }
                 Self::StamError(_) => unreachable!("Already handled"),
             }
             state.end()
@@ -140,6 +252,9 @@ impl IntoResponse for ApiError {
             Self::InternalError(..) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::PermissionDenied(..) => StatusCode::FORBIDDEN,
             Self::NotAcceptable(..) => StatusCode::NOT_ACCEPTABLE,
+            Self::InvalidArgument(..) => StatusCode::BAD_REQUEST,
+            Self::Timeout(..) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Conflict(..) => StatusCode::CONFLICT,
             _ => StatusCode::NOT_FOUND,
         };
         (statuscode, Json(self)).into_response()
@@ -0,0 +1,185 @@
+use crate::budget::QueryBudget;
+use crate::common::{incomplete_keyword, parse_query, ApiError};
+use crate::offsets::Position;
+use serde_json::value::Value;
+use stam::{Query, StamError};
+
+/// Parses a query without running it, and returns a JSON description of its structure,
+/// or of the syntax error (including its position) if the query is invalid.
+/// Finding the position of an error takes a parser run per token, so this checks the budget in between.
+pub fn explain(querystring: &str, budget: &QueryBudget) -> Result<Value, ApiError> {
+    if let Some((bytepos, keyword)) = incomplete_keyword(querystring) {
+        return Ok(syntax_error(
+            querystring,
+            &format!("Expected a result type after {}", keyword),
+            Some(bytepos),
+        ));
+    }
+    let result = parse_query(querystring);
+    Ok(match result {
+        Ok((query, remainder)) if remainder.trim().is_empty() => {
+            let mut variables = query.names();
+            variables.sort_unstable();
+            serde_json::json!({
+                "valid": true,
+                "readonly": is_readonly(&query),
+                "variables": variables,
+                "query": query_to_json(&query),
+            })
+        }
+        Ok((_, remainder)) => {
+            //the parser stops at the end of the first query, anything after it is an error
+            let bytepos = querystring.trim_end().len() - remainder.len();
+            syntax_error(
+                querystring,
+                &format!("Expected end of query, got '{}'", remainder.trim()),
+                Some(bytepos),
+            )
+        }
+        Err(StamError::QuerySyntaxError(message, _)) => syntax_error(
+            querystring,
+            &message,
+            Some(error_position(querystring, budget)?),
+        ),
+        Err(err) => syntax_error(querystring, &err.to_string(), None),
+    })
+}
+
+/// A query is read-only only if it and all of its subqueries are
+fn is_readonly(query: &Query) -> bool {
+    query.querytype().readonly() && query.subqueries().all(is_readonly)
+}
+
+fn query_to_json(query: &Query) -> Value {
+    let mut constraints = Vec::new();
+    for (constraint, attributes) in query.constraints_with_attributes() {
+        constraints.push(serde_json::json!({
+            "keyword": constraint.keyword(),
+            "statement": constraint.to_string().ok(),
+            "attributes": attributes,
+        }));
+    }
+    serde_json::json!({
+        "type": query.querytype().as_str(),
+        "resulttype": query.resulttype_as_str(),
+        "variable": query.name(),
+        "optional": !query.qualifier().as_str().is_empty(),
+        "attributes": query.attributes().collect::<Vec<_>>(),
+        "constraints": constraints,
+        "subqueries": query.subqueries().map(query_to_json).collect::<Vec<_>>(),
+    })
+}
+
+fn syntax_error(querystring: &str, message: &str, bytepos: Option<usize>) -> Value {
+    serde_json::json!({
+        "valid": false,
+        "error": {
+            "name": "QuerySyntaxError",
+            "message": message,
+            "position": bytepos.map(|bytepos| Position::new(querystring, querystring[..bytepos].chars().count())),
+        }
+    })
+}
+
+/// STAM's syntax errors do not carry a position, and the parser only returns the remainder of the querystring when it succeeds.
+/// So we find the longest prefix (up to a token boundary) that the parser accepts as a complete query, closing any open subquery blocks,
+/// the error is in the first token after it. Returns a byte offset.
+fn error_position(querystring: &str, budget: &QueryBudget) -> Result<usize, ApiError> {
+    let mut valid = 0;
+    for (cut, depth) in cut_points(querystring) {
+        budget.check()?;
+        let prefix = format!("{}{}", &querystring[..cut], " }".repeat(depth));
+        if parse_query(&prefix).is_ok_and(|(_, remainder)| remainder.trim().is_empty()) {
+            valid = cut;
+        }
+    }
+    Ok(valid + querystring[valid..].len() - querystring[valid..].trim_start().len())
+}
+
+/// Returns the token boundaries outside string literals (as byte offsets), along with the number of subquery blocks that are open at each of them
+fn cut_points(querystring: &str) -> Vec<(usize, usize)> {
+    let mut cuts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth: usize = 0;
+    let mut prev: Option<char> = None;
+    for (bytepos, c) in querystring.char_indices() {
+        if !quoted
+            && (c.is_whitespace()
+                || matches!(c, ';' | '{' | '}' | '|')
+                || matches!(prev, Some(';' | '{' | '}' | '|')))
+        {
+            cuts.push((bytepos, depth));
+        }
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == '{' && !quoted {
+            depth += 1;
+        } else if c == '}' && !quoted {
+            depth = depth.saturating_sub(1);
+        }
+        prev = Some(c);
+    }
+    if !quoted {
+        cuts.push((querystring.len(), depth));
+    }
+    cuts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_positions() {
+        for (querystring, expected) in [
+            ("SELECT RESOURCE ?x; }", Some(18)),
+            (r#"SELECT RESOURCE ?x WHERE ID "é"#, Some(25)),
+            (r#"SELECT RESOURCE ?x WHERE FOO "x";"#, Some(25)),
+            (r#"SELECT RESOURCE ?x WHERE ID "x"; } "#, Some(33)),
+            (
+                r#"SELECT RESOURCE ?x WHERE ID "x"; { SELECT TEXT ?t WHERE FOO ?x; }"#,
+                Some(56),
+            ),
+            (
+                r#"SELECT RESOURCE ?x WHERE ID "x"; { SELECT TEXT ?t WHERE RESOURCE ?x;"#,
+                Some(68),
+            ),
+            (r#"SELECT RESOURCE ?x WHERE ID "x;y"; x"#, Some(35)),
+            ("FOO", Some(0)),
+            //keywords the parser would panic on
+            ("SELECT", Some(6)),
+            (" @IDTAG ADD ", Some(11)),
+            (r#"SELECT DATA ?d; { SELECTé DATASET ?s; }"#, Some(24)),
+            (r#"SELECT RESOURCE ?x WHERE ID "x"; { SELECT"#, Some(41)),
+            (
+                r#"SELECT RESOURCE ?x WHERE ID "x"; { SELECT TEXT ?t WHERE RESOURCE ?x; }"#,
+                None,
+            ),
+        ] {
+            let result = explain(querystring, &QueryBudget::new(None)).unwrap();
+            assert_eq!(
+                result["error"]["position"]["bytes"].as_u64(),
+                expected,
+                "{} {}",
+                querystring,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn budget() {
+        let budget = QueryBudget::new(Some(std::time::Duration::ZERO));
+        //valid queries need no search for an error position
+        assert!(explain("SELECT RESOURCE ?x", &budget).is_ok());
+        assert!(matches!(
+            explain(r#"SELECT RESOURCE ?x WHERE FOO "x";"#, &budget),
+            Err(ApiError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::common::{parse_query, ApiError};
+use crate::merge::{MergePlan, MergeReport};
+use serde::{Deserialize, Serialize};
+use stam::AnnotationStore;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// A full snapshot is taken every so many revisions, this bounds the number of queries that have to be replayed to restore a revision
+const SNAPSHOT_INTERVAL: usize = 100;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// A change to a store
+pub enum Change {
+    /// The state of the store when recording its history started
+    Initial,
+    /// One or more queries, applied together
+    Query {
+        /// The queries in STAMQL, in the order they were applied
+        queries: Vec<String>,
+    },
+    /// A text resource was added
+    Resource {
+        /// The identifier of the resource
+        id: String,
+    },
+    /// The store was reverted to the state of an earlier revision
+    Revert {
+        /// The revision that was restored
+        revision: usize,
+    },
+    /// The store was restored from a backup
+    Restore {
+        /// The backup that was restored
+        backup: u64,
+    },
+    /// Another store was merged into the store
+    Merge {
+        /// The store that was merged
+        source: String,
+        /// What the merge did
+        report: Box<MergeReport>,
+        /// The changes that were made, only kept for the write-ahead log
+        #[serde(skip)]
+        #[schema(ignore)]
+        plan: Box<MergePlan>,
+    },
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// An entry in the change log of a store
+pub struct Revision {
+    /// Revision number, the initial state is revision 0
+    pub revision: usize,
+
+    /// Time of the change, in seconds since the unix epoch
+    pub timestamp: u64,
+
+    /// The user who made the change, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// What was changed
+    pub change: Change,
+}
+
+/// The append-only change log of a single store, along with snapshots of the store at certain revisions.
+/// It is kept in a directory alongside the store. All changes must be recorded while holding the store's write lock.
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn log_filename(&self) -> PathBuf {
+        self.dir.join("log.jsonl")
+    }
+
+    fn snapshot_filename(&self, revision: usize) -> PathBuf {
+        self.dir.join(format!("{}.store.stam.cbor", revision))
+    }
+
+    /// Returns all revisions, oldest first. A store without history has no revisions.
+    pub fn revisions(&self) -> Result<Vec<Revision>, ApiError> {
+        let filename = self.log_filename();
+        if !filename.exists() {
+            return Ok(Vec::new());
+        }
+        let file = std::fs::File::open(filename)
+            .map_err(|_| ApiError::InternalError("Unable to read history"))?;
+        let mut revisions = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|_| ApiError::InternalError("Unable to read history"))?;
+            revisions.push(
+                serde_json::from_str(&line)
+                    .map_err(|_| ApiError::InternalError("Invalid history file"))?,
+            );
+        }
+        Ok(revisions)
+    }
+
+    /// Starts recording history for a store, if that hasn't happened yet. Must be called before changing the store.
+    pub fn start(&self, store: &AnnotationStore) -> Result<(), ApiError> {
+        if self.log_filename().exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|_| ApiError::InternalError("Unable to create history directory"))?;
+        self.record(store, Change::Initial, None).map(|_| ())
+    }
+
+    /// Records a change that has just been applied to the store
+    pub fn record(
+        &self,
+        store: &AnnotationStore,
+        change: Change,
+        user: Option<&str>,
+    ) -> Result<Revision, ApiError> {
+        let revision = Revision {
+            revision: self.next_revision()?,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            user: user.map(|s| s.to_string()),
+            change,
+        };
+        //changes that can not be replayed from the log require a snapshot
+        if !matches!(revision.change, Change::Query { .. })
+            || revision.revision.is_multiple_of(SNAPSHOT_INTERVAL)
+        {
+            std::fs::write(self.snapshot_filename(revision.revision), snapshot(store)?)
+                .map_err(|_| ApiError::InternalError("Unable to write history snapshot"))?;
+        }
+        let mut line = serde_json::to_string(&revision)
+            .map_err(|_| ApiError::InternalError("Unable to serialize history"))?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_filename())
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|_| ApiError::InternalError("Unable to write history"))?;
+        Ok(revision)
+    }
+
+    /// Returns the number the next revision will get
+    fn next_revision(&self) -> Result<usize, ApiError> {
+        let filename = self.log_filename();
+        if !filename.exists() {
+            return Ok(0);
+        }
+        let file = std::fs::File::open(filename)
+            .map_err(|_| ApiError::InternalError("Unable to read history"))?;
+        Ok(BufReader::new(file).lines().count())
+    }
+
+    /// Reconstructs the state of the store at the given revision, from the nearest snapshot before it
+    /// and the queries that were applied after that snapshot.
+    pub fn state(&self, revision: usize) -> Result<AnnotationStore, ApiError> {
+        let revisions = self.revisions()?;
+        let mut replay: Vec<&[String]> = Vec::new();
+        let mut current = revision;
+        let mut store = loop {
+            let entry = revisions
+                .get(current)
+                .ok_or(ApiError::NotFound("No such revision"))?;
+            let filename = self.snapshot_filename(current);
+            if filename.exists() {
+                let data = std::fs::read(filename)
+                    .map_err(|_| ApiError::InternalError("Unable to read history snapshot"))?;
+                break restore(&data)?;
+            }
+            match &entry.change {
+                Change::Query { queries } if current > 0 => {
+                    replay.push(queries);
+                    current -= 1;
+                }
+                _ => return Err(ApiError::InternalError("History snapshot is missing")),
+            }
+        };
+        for queries in replay.into_iter().rev() {
+            for querystring in queries {
+                let (query, _) = parse_query(querystring)?;
+                //mutations are applied immediately, the results are not needed
+                store.query_mut(query)?.for_each(drop);
+            }
+        }
+        Ok(store)
+    }
+}
+
+/// Returns a complete copy of the store's internal state (including all indices) in STAM's binary (CBOR) serialisation
+pub fn snapshot(store: &AnnotationStore) -> Result<Vec<u8>, ApiError> {
+    minicbor::to_vec(store)
+        .map_err(|_| ApiError::InternalError("Unable to take a snapshot of the store"))
+}
+
+/// Restores a store from a snapshot
+pub fn restore(data: &[u8]) -> Result<AnnotationStore, ApiError> {
+    minicbor::decode(data).map_err(|_| ApiError::InternalError("Unable to restore the store"))
+}
@@ -1,6 +1,7 @@
 use axum::{
     body::Body, extract::Path, extract::Query, extract::State, http::HeaderMap, http::HeaderValue,
-    http::Request, routing::get, routing::post, Form, Router,
+    http::Request, http::Uri, routing::delete, routing::get, routing::post, routing::put, Form,
+    Json, Router, ServiceExt,
 };
 use clap::Parser;
 use serde::Deserialize;
@@ -11,27 +12,61 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
+use tower::util::MapRequestLayer;
+use tower::Layer;
 use tower_http::trace::TraceLayer;
 use tracing::{debug, error};
 
 use utoipa::{OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
 
-use stam::{Config, Offset, QueryIter, StamError, Text};
+use stam::{
+    Annotation, AnnotationStore, Config, QueryIter, QueryResultItem, ResultItem,
+    ResultTextSelection, StamError, Text, TextSelectionIterator, TextSelectionOperator,
+};
 use stamtools::view::HtmlWriter;
 
 mod apidocs;
+mod backup;
+mod batch;
+mod budget;
+mod cache;
 mod common;
+mod explain;
+mod history;
+mod merge;
+mod metadata;
 mod multistore;
-use common::{ApiError, ApiResponse};
-use multistore::StorePool;
+mod offsets;
+mod savedqueries;
+mod tabular;
+mod transaction;
+mod ui;
+mod wal;
+use backup::Backup;
+use batch::BatchRequest;
+use budget::QueryBudget;
+use cache::CacheKey;
+use common::{html_escape, parse_query, quote_stamql, ApiError, ApiResponse};
+use history::{Change, Revision};
+use merge::{Collisions, MergeReport};
+use metadata::StoreMetadata;
+use multistore::{StoreInfo, StorePool};
+use offsets::{rfc5147_to_offset, textquote_to_offset, textselection_to_json_value, OffsetUnit};
+use savedqueries::SavedQuery;
 
-pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
-const CONTENT_TYPE_JSON: &'static str = "application/json";
-const CONTENT_TYPE_JSONLD: &'static str = "application/ld+json";
-const CONTENT_TYPE_HTML: &'static str = "text/html";
-const CONTENT_TYPE_TEXT: &'static str = "text/plain";
+/// Interval at which store files are checked for changes made by others
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_JSONLD: &str = "application/ld+json";
+const CONTENT_TYPE_HTML: &str = "text/html";
+const CONTENT_TYPE_TEXT: &str = "text/plain";
+const CONTENT_TYPE_TSV: &str = "text/tab-separated-values";
+const CONTENT_TYPE_CSV: &str = "text/csv";
+/// Header identifying the user making a request, as set by an authenticating reverse proxy
+const USER_HEADER: &str = "X-Remote-User";
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -73,6 +108,48 @@ struct Args {
     )]
     unload_time: u64,
 
+    #[arg(
+        long,
+        default_value_t = 300,
+        help = "Number of seconds between automatic saves of stores with unsaved changes, 0 disables autosaving (stores are then only saved when they are unloaded)"
+    )]
+    autosave: u64,
+
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "Maximum number of seconds a query may run before it is aborted, 0 for no limit"
+    )]
+    query_timeout: u64,
+
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "Maximum number of read-only query results to keep in memory, 0 disables caching"
+    )]
+    cache_size: usize,
+
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "Maximum size in megabytes of the read-only query results to keep in memory, 0 disables caching"
+    )]
+    cache_memory: usize,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Record the history of all changes to stores, so stores can be reverted to earlier revisions. The history is kept alongside each store in the base directory."
+    )]
+    history: bool,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "Number of backups of earlier versions to keep per store when saving, 0 disables backups. Backups are kept alongside each store in the base directory."
+    )]
+    backups: usize,
+
     #[arg(
         short,
         long,
@@ -112,6 +189,9 @@ struct Args {
     paths(
         list_stores,
         get_query,
+        get_explain,
+        post_explain,
+        get_cache_metrics,
         create_store,
         create_resource,
         get_annotation_list,
@@ -119,6 +199,23 @@ struct Args {
         get_resource_list,
         get_resource,
         get_textselection,
+        post_batch,
+        post_transaction,
+        get_history,
+        post_revert,
+        get_backups,
+        post_restore_backup,
+        post_reload,
+        post_copy,
+        post_rename,
+        post_merge,
+        get_store_stats,
+        get_metadata,
+        put_metadata,
+        list_saved_queries,
+        run_saved_query,
+        save_query,
+        delete_saved_query,
     ),
     tags(
         (name = "stamd", description = "WebAPI for stam")
@@ -156,6 +253,15 @@ async fn main() {
         args.extension,
         args.readonly,
         args.unload_time,
+        if args.query_timeout > 0 {
+            Some(Duration::from_secs(args.query_timeout))
+        } else {
+            None
+        },
+        args.cache_size,
+        args.cache_memory * 1024 * 1024,
+        args.history,
+        args.backups,
         args.no_extra_target,
         webannoconfig,
         Config::default(),
@@ -183,6 +289,35 @@ async fn main() {
         }
     });
 
+    let storepool_watch = storepool.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        match storepool_watch.check_changes() {
+            Err(e) => error!("Checking for changes on disk failed! {:?}", e),
+            Ok(v) => {
+                if args.debug && !v.is_empty() {
+                    debug!("{} store(s) changed on disk", v.len());
+                }
+            }
+        }
+    });
+
+    if !args.readonly && args.autosave > 0 {
+        let storepool_autosave = storepool.clone();
+        let autosave_interval = Duration::from_secs(args.autosave);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(autosave_interval);
+            match storepool_autosave.autosave() {
+                Err(e) => error!("Autosave failed! {:?}", e),
+                Ok(v) => {
+                    if args.debug {
+                        debug!("Autosaved {} store(s)", v.len());
+                    }
+                }
+            }
+        });
+    }
+
     let app = Router::new()
         .route("/", get(list_stores))
         .route("/{store_id}", post(create_store))
@@ -197,23 +332,54 @@ async fn main() {
             "/{store_id}/resources/{resource_id}/{begin}/{end}",
             get(get_textselection),
         )
+        .route("/{store_id}/_batch", post(post_batch))
+        .route("/{store_id}/_transaction", post(post_transaction))
+        .route("/{store_id}/history", get(get_history))
+        .route("/{store_id}/history/{revision}/revert", post(post_revert))
+        .route("/{store_id}/_reload", post(post_reload))
+        .route("/{store_id}/_copy", post(post_copy))
+        .route("/{store_id}/_rename", post(post_rename))
+        .route("/{store_id}/_merge", post(post_merge))
+        .route("/{store_id}/backups", get(get_backups))
+        .route(
+            "/{store_id}/backups/{backup}/restore",
+            post(post_restore_backup),
+        )
+        .route("/_ui/{asset}", get(get_asset))
+        .route("/_explain", get(get_explain))
+        .route("/_explain", post(post_explain))
+        .route("/_cache", get(get_cache_metrics))
+        .route("/{store_id}/stats", get(get_store_stats))
+        .route("/{store_id}/metadata", get(get_metadata))
+        .route("/{store_id}/metadata", put(put_metadata))
+        .route("/{store_id}/queries", get(list_saved_queries))
+        .route("/{store_id}/queries/{name}", get(run_saved_query))
+        .route("/{store_id}/queries/{name}", put(save_query))
+        .route("/{store_id}/queries/{name}", delete(delete_saved_query))
         .route("/{store_id}/resources", get(get_resource_list))
         .route("/{store_id}/resources/{resource_id}", get(get_resource))
         .route("/{store_id}/resources/{resource_id}", post(create_resource))
         .merge(SwaggerUi::new("/swagger-ui").url("/api-doc/openapi.json", ApiDoc::openapi()))
         .layer(TraceLayer::new_for_http())
         .with_state(storepool.clone());
+    let storepool_shutdown = storepool.clone();
 
     //allow trailing slashes as well: (conflicts with swagger-ui!)
     //let app = NormalizePathLayer::trim_trailing_slash().layer(app);
 
+    //stores in subdirectories are addressed with multiple path segments, this has to be resolved before routing
+    let app = MapRequestLayer::new(move |request: Request<Body>| {
+        resolve_nested_store(&storepool, request)
+    })
+    .layer(app);
+
     eprintln!("[stamd] listening on {}", args.bind);
     let listener = tokio::net::TcpListener::bind(args.bind).await.unwrap();
     axum::serve(
-        listener, app,
-        //ServiceExt::<axum::http::Request<Body>>::into_make_service(app),
+        listener,
+        ServiceExt::<Request<Body>>::into_make_service(app),
     )
-    .with_graceful_shutdown(shutdown_signal(storepool))
+    .with_graceful_shutdown(shutdown_signal(storepool_shutdown))
     .await
     .unwrap();
 }
@@ -249,34 +415,82 @@ async fn shutdown_signal(storepool: Arc<StorePool>) {
 #[utoipa::path(
     get,
     path = "/",
+    params(
+        ("sort" = Option<String>, Query, description = "Sort the stores by `id` (default), `size` or `modified`"),
+        ("reverse" = Option<bool>, Query, description = "Reverse the sort order"),
+        ("prefix" = Option<String>, Query, description = "Only list stores whose identifier starts with this prefix, e.g. a subdirectory like `project/`"),
+        ("loaded" = Option<bool>, Query, description = "Only list stores that are (`true`) or are not (`false`) currently loaded"),
+    ),
     responses(
-        (status = 200, body = [String], description = "Returns a simple list of all available annotation stores"),
+        (status = 200, body = [StoreInfo], description = "Returns all available annotation stores with their file size, modification time, metadata and whether they are loaded; loaded stores also report unsaved changes and the number of annotations, resources and datasets (JSON). Alternatively returns a DCAT catalogue of all stores (JSON-LD) or an interactive query interface (HTML). Stores in subdirectories are included, their identifiers are paths relative to the base directory (e.g. `project/subcorpus/x`)."),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if a parameter has an invalid value", content_type = "application/json")
     )
 )]
-/// Runs all available annotation stores or provide a very simple webinterface
+/// Lists all available annotation stores or provides an interactive query interface
 async fn list_stores(
     storepool: State<Arc<StorePool>>,
+    Query(params): Query<HashMap<String, String>>,
     request: Request<Body>,
 ) -> Result<ApiResponse, ApiError> {
-    let extension = format!(".{}", storepool.extension());
-    let mut store_ids: Vec<String> = Vec::new();
-    for entry in std::fs::read_dir(storepool.basedir())
-        .map_err(|_| ApiError::InternalError("Unable to read base directory"))?
-    {
-        let entry = entry.unwrap();
-        if let Some(filename) = entry.file_name().to_str() {
-            if let Some(pos) = filename.find(&extension) {
-                store_ids.push(filename[0..pos].to_string());
-            }
+    let mut stores = storepool.list_stores()?;
+    if let Some(prefix) = params.get("prefix") {
+        stores.retain(|store| store.id.starts_with(prefix.as_str()));
+    }
+    if let Some(loaded) = bool_parameter(
+        params.get("loaded").map(|s| s.as_str()),
+        "The loaded parameter must be true or false",
+    )? {
+        stores.retain(|store| store.loaded == loaded);
+    }
+    //stores are sorted by identifier already, sorting is stable so that remains the secondary order
+    match params.get("sort").map(|s| s.as_str()) {
+        None | Some("id") => {}
+        Some("size") => stores.sort_by_key(|store| store.size),
+        Some("modified") => stores.sort_by_key(|store| store.modified),
+        Some(_) => {
+            return Err(ApiError::InvalidArgument(
+                "The sort parameter must be id, size or modified",
+            ))
         }
     }
-    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_HTML, CONTENT_TYPE_JSON]) {
-        Ok(CONTENT_TYPE_HTML) => Ok(ApiResponse::QueryUI(store_ids)),
-        Ok(CONTENT_TYPE_JSON) => {
-            let store_ids: Vec<serde_json::Value> =
-                store_ids.into_iter().map(|s| s.into()).collect();
-            Ok(ApiResponse::JsonList(store_ids))
+    if bool_parameter(
+        params.get("reverse").map(|s| s.as_str()),
+        "The reverse parameter must be true or false",
+    )?
+    .unwrap_or(false)
+    {
+        stores.reverse();
+    }
+    match negotiate_content_type(
+        request.headers(),
+        &[CONTENT_TYPE_HTML, CONTENT_TYPE_JSON, CONTENT_TYPE_JSONLD],
+    ) {
+        Ok(CONTENT_TYPE_HTML) => Ok(ApiResponse::QueryUI(
+            stores.into_iter().map(|store| store.id).collect(),
+        )),
+        Ok(CONTENT_TYPE_JSONLD) => {
+            let datasets: Vec<serde_json::Value> = stores
+                .into_iter()
+                .map(|store| {
+                    store.metadata.unwrap_or_default().to_dcat(
+                        &store.id,
+                        &store_url(&storepool, &store.id),
+                        false,
+                    )
+                })
+                .collect();
+            let catalog = serde_json::json!({
+                "@context": metadata::dcat_context(),
+                "@id": storepool.baseurl(),
+                "@type": "dcat:Catalog",
+                "dcat:dataset": datasets,
+            });
+            Ok(ApiResponse::RawJsonLd(catalog.to_string()))
         }
+        Ok(CONTENT_TYPE_JSON) => Ok(ApiResponse::Json(
+            serde_json::to_value(stores)
+                .map_err(|_| ApiError::InternalError("Unable to serialize store list"))?,
+        )),
         _ => Err(ApiError::NotAcceptable(
             "Accept headed could not be satisfied (try application/json)",
         )),
@@ -303,19 +517,34 @@ async fn create_store(
 #[utoipa::path(
     post,
     path = "/{store_id}/resources/{resource_id}",
+    params(
+        ("save" = Option<bool>, Query, description = "Save the store before returning, rather than leaving that to the autosave"),
+    ),
     request_body(content_type = "text/plain", description = "The full text of the resource"),
     responses(
         (status = 201, description = "Returned when successfully created"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the store is configured as read-only or the resource already exists", content_type = "application/json")
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance the store is configured as read-only or the resource already exists", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json")
     )
 )]
 /// Create a new text resource, the request body contains the text.
 async fn create_resource(
     Path((store_id, resource_id)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
     storepool: State<Arc<StorePool>>,
+    headers: HeaderMap<HeaderValue>,
     text: String,
 ) -> Result<ApiResponse, ApiError> {
-    storepool.new_resource(&store_id, &resource_id, text)?;
+    let save = save_requested(params.get("save").map(|s| s.as_str()))?;
+    storepool.new_resource(
+        &store_id,
+        &resource_id,
+        text,
+        remote_user(&headers).as_deref(),
+    )?;
+    if save {
+        storepool.save(&store_id)?;
+    }
     Ok(ApiResponse::Created())
 }
 
@@ -325,20 +554,29 @@ async fn create_resource(
     params(
         ("store_id" = String, Path, description = "The identifier of the store"),
         ("query" = String, Query, description = "A query in STAMQL, see <https://github.com/annotation/stam/tree/master/extensions/stam-query> for the syntax.", allow_reserved),
-        ("use" = Option<String>, Query, description = "Select a single variable from the query (by name, without '?' prefix), to constrain the result set accordingly.")
+        ("use" = Option<String>, Query, description = "Select a single variable from the query (by name, without '?' prefix), to constrain the result set accordingly."),
+        ("columns" = Option<String>, Query, description = "(for TSV/CSV output only) Comma-separated list of columns to output. Each column is a variable name, optionally followed by a colon and a projection: `id`, `text`, `begin`, `end`, `resource`, `type`, or `data(\"set\",\"key\")` for the value of a specific data key. Defaults to all variables."),
+        ("delimiter" = Option<String>, Query, description = "(for plain text output only) The delimiter between results, defaults to a newline. The escape sequences `\\n` and `\\t` are recognised."),
+        ("vardelimiter" = Option<String>, Query, description = "(for plain text output only) The delimiter between variables in a result, and between the parts of a text that consists of multiple parts, defaults to a tab. The escape sequences `\\n` and `\\t` are recognised."),
+        ("save" = Option<bool>, Query, description = "(for queries that change the store only) Save the store before returning, rather than leaving that to the autosave"),
     ),
     responses(
-        (status = 200, description = "Query result. Several return types are supported via content negotation, but not all content types can be used for all queries. Most notably, the plain text type only works if the query produces a single item that holds text as result.",content(
+        (status = 200, description = "Query result. Several return types are supported via content negotation, but not all content types can be used for all queries. Most notably, the plain text type outputs empty strings for items that hold no text, and JSON-LD only works if the (selected) variable is an annotation.",content(
             ([BTreeMap<String,apidocs::StamJson>] = "application/json"),
             ([apidocs::StamJson] = "application/json"),
+            (apidocs::WebAnnotationCollection = "application/ld+json"),
             (String = "text/html"),
             (String = "text/plain"),
+            (String = "text/tab-separated-values"),
+            (String = "text/csv"),
         )),
         (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered for your query.", content_type = "application/json"),
         (status = 404, body = apidocs::StamError, description = "Return when the query is invalid or another error occurs", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "Returned with name `MissingArgument` if you forget the 'query' parameter", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance when you send a query that edits the data but the store is configured as read-only", content_type = "application/json")
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance when you send a query that edits the data but the store is configured as read-only", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when the query exceeds the time limit", content_type = "application/json")
     )
 )]
 /// Run a query on an annotation store. The query is formulated in STAMQL.
@@ -350,12 +588,19 @@ async fn get_query(
 ) -> Result<ApiResponse, ApiError> {
     if let Some(querystring) = params.get("query") {
         run_query(
-            store_id.as_str(),
-            querystring,
-            params.get("use").map(|s| s.as_str()),
-            storepool,
-            request.headers(),
+            store_id,
+            querystring.to_string(),
+            ResultOptions::new(
+                params.get("use").map(|s| s.as_str()),
+                params.get("columns").map(|s| s.as_str()),
+                params.get("delimiter").map(|s| s.as_str()),
+                params.get("vardelimiter").map(|s| s.as_str()),
+            )?,
+            save_requested(params.get("save").map(|s| s.as_str()))?,
+            storepool.0,
+            request.headers().clone(),
         )
+        .await
     } else {
         Err(ApiError::MissingArgument("query"))
     }
@@ -371,6 +616,18 @@ struct QueryForm {
 
     /// A variable from the above query to return in the result set (without leading ?)
     r#use: Option<String>,
+
+    /// Columns for tabular output (TSV/CSV), see the GET endpoint for the syntax
+    columns: Option<String>,
+
+    /// Delimiter between results for plain text output (defaults to a newline)
+    delimiter: Option<String>,
+
+    /// Delimiter between variables for plain text output (defaults to a tab)
+    vardelimiter: Option<String>,
+
+    /// Save the store before returning, for queries that change the store (`true` or `false`)
+    save: Option<String>,
 }
 
 #[utoipa::path(
@@ -378,17 +635,22 @@ struct QueryForm {
     path = "/query",
     request_body( content_type = "multipart/form-data", content = QueryForm),
     responses(
-        (status = 200, description = "Query result. Several return types are supported via content negotation, but not all content types can be used for all queries. Most notably, the plain text type only works if the query produces a single item that holds text as result.",content(
+        (status = 200, description = "Query result. Several return types are supported via content negotation, but not all content types can be used for all queries. Most notably, the plain text type outputs empty strings for items that hold no text, and JSON-LD only works if the (selected) variable is an annotation.",content(
             ([BTreeMap<String,apidocs::StamJson>] = "application/json"),
             ([apidocs::StamJson] = "application/json"),
+            (apidocs::WebAnnotationCollection = "application/ld+json"),
             (String = "text/html"),
             (String = "text/plain"),
+            (String = "text/tab-separated-values"),
+            (String = "text/csv"),
         )),
         (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered for your query.", content_type = "application/json"),
         (status = 404, body = apidocs::StamError, description = "Return when the query is invalid or another error occurs", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "Returned with name `MissingArgument` if you forget the 'query' parameter", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
-        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance when you send a query that edits the data but the store is configured as read-only", content_type = "application/json")
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when permission is denied, for instance when you send a query that edits the data but the store is configured as read-only", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when the query exceeds the time limit", content_type = "application/json")
     )
 )]
 /// Run a query on an annotation store. The query is formulated in STAMQL.
@@ -398,12 +660,198 @@ async fn post_query(
     Form(queryform): Form<QueryForm>,
 ) -> Result<ApiResponse, ApiError> {
     run_query(
-        queryform.store.as_str(),
-        queryform.query.as_str(),
-        queryform.r#use.as_ref().map(|s| s.as_str()),
-        storepool,
-        &headers,
+        queryform.store,
+        queryform.query,
+        ResultOptions::new(
+            queryform.r#use.as_deref(),
+            queryform.columns.as_deref(),
+            queryform.delimiter.as_deref(),
+            queryform.vardelimiter.as_deref(),
+        )?,
+        save_requested(queryform.save.as_deref())?,
+        storepool.0,
+        headers,
+    )
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/_explain",
+    params(
+        ("query" = String, Query, description = "A query in STAMQL, see <https://github.com/annotation/stam/tree/master/extensions/stam-query> for the syntax.", allow_reserved),
+    ),
+    responses(
+        (status = 200, body = apidocs::QueryExplanation, description = "Returns the parsed structure of the query, or the syntax error and its position if the query is invalid. The query is not run."),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `MissingArgument` if you forget the 'query' parameter", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when finding the position of a syntax error exceeds the time limit for queries", content_type = "application/json"),
+    )
+)]
+/// Parses a query without running it, this can be used to validate queries
+async fn get_explain(
+    Query(mut params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    if let Some(querystring) = params.remove("query") {
+        run_explain(querystring, storepool.0).await
+    } else {
+        Err(ApiError::MissingArgument("query"))
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ExplainForm {
+    /// A query in STAMQL
+    query: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/_explain",
+    request_body( content_type = "multipart/form-data", content = ExplainForm),
+    responses(
+        (status = 200, body = apidocs::QueryExplanation, description = "Returns the parsed structure of the query, or the syntax error and its position if the query is invalid. The query is not run."),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when finding the position of a syntax error exceeds the time limit for queries", content_type = "application/json"),
+    )
+)]
+/// Parses a query without running it, this can be used to validate queries
+async fn post_explain(
+    storepool: State<Arc<StorePool>>,
+    Form(explainform): Form<ExplainForm>,
+) -> Result<ApiResponse, ApiError> {
+    run_explain(explainform.query, storepool.0).await
+}
+
+/// Explains a query on a blocking thread, within the same time budget as queries
+async fn run_explain(
+    querystring: String,
+    storepool: Arc<StorePool>,
+) -> Result<ApiResponse, ApiError> {
+    let budget = QueryBudget::new(storepool.query_timeout());
+    let _guard = budget.cancel_on_drop();
+    tokio::task::spawn_blocking(move || explain::explain(&querystring, &budget))
+        .await
+        .map_err(|_| ApiError::InternalError("Query explanation failed"))?
+        .map(ApiResponse::Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/queries",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, body = BTreeMap<String, SavedQuery>, description = "Returns all saved queries for the store, by name"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+    )
+)]
+/// Lists the saved queries of an annotation store
+async fn list_saved_queries(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    let queries = storepool.saved_queries(&store_id)?;
+    Ok(ApiResponse::Json(serde_json::to_value(queries).map_err(
+        |_| ApiError::InternalError("Unable to serialize saved queries"),
+    )?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/queries/{name}",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("name" = String, Path, description = "The name of the saved query"),
+        ("params" = HashMap<String, String>, Query, style = Form, explode, description = "Values for the parameters of the saved query, by name. The parameters `use`, `columns`, `delimiter`, `vardelimiter` and `save` are reserved and have the same meaning as for regular queries."),
+    ),
+    responses(
+        (status = 200, description = "Query result. Several return types are supported via content negotation, the same as for regular queries.",content(
+            ([BTreeMap<String,apidocs::StamJson>] = "application/json"),
+            ([apidocs::StamJson] = "application/json"),
+            (apidocs::WebAnnotationCollection = "application/ld+json"),
+            (String = "text/html"),
+            (String = "text/plain"),
+            (String = "text/tab-separated-values"),
+            (String = "text/csv"),
+        )),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if a required parameter is missing, an unknown parameter is passed, or a value can not be safely used in a query", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store or saved query does not exist", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when the query exceeds the time limit", content_type = "application/json")
+    )
+)]
+/// Runs a saved query on an annotation store, substituting its parameters
+async fn run_saved_query(
+    Path((store_id, name)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    let querystring = storepool
+        .saved_query(&store_id, &name)?
+        .instantiate(&params)?;
+    run_query(
+        store_id,
+        querystring,
+        ResultOptions::new(
+            params.get("use").map(|s| s.as_str()),
+            params.get("columns").map(|s| s.as_str()),
+            params.get("delimiter").map(|s| s.as_str()),
+            params.get("vardelimiter").map(|s| s.as_str()),
+        )?,
+        save_requested(params.get("save").map(|s| s.as_str()))?,
+        storepool.0,
+        request.headers().clone(),
+    )
+    .await
+}
+
+#[utoipa::path(
+    put,
+    path = "/{store_id}/queries/{name}",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("name" = String, Path, description = "The name of the saved query, may only contain alphanumeric characters, dashes and underscores"),
+    ),
+    request_body(content_type = "application/json", content = SavedQuery),
+    responses(
+        (status = 201, description = "Returned when successfully saved"),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if the name or parameters are invalid", content_type = "application/json"),
+        (status = 404, body = apidocs::StamError, description = "Returned when the query is invalid", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json")
     )
+)]
+/// Adds or replaces a saved query for an annotation store
+async fn save_query(
+    Path((store_id, name)): Path<(String, String)>,
+    storepool: State<Arc<StorePool>>,
+    Json(query): Json<SavedQuery>,
+) -> Result<ApiResponse, ApiError> {
+    storepool.save_query(&store_id, &name, query)?;
+    Ok(ApiResponse::Created())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{store_id}/queries/{name}",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("name" = String, Path, description = "The name of the saved query"),
+    ),
+    responses(
+        (status = 200, description = "Returned when successfully deleted"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store or saved query does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json")
+    )
+)]
+/// Deletes a saved query from an annotation store
+async fn delete_saved_query(
+    Path((store_id, name)): Path<(String, String)>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    storepool.delete_query(&store_id, &name)?;
+    Ok(ApiResponse::Deleted())
 }
 
 #[utoipa::path(
@@ -474,6 +922,134 @@ async fn get_resource_list(
     })
 }
 
+#[utoipa::path(
+    get,
+    path = "/{store_id}/stats",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, body = BTreeMap<String, usize>, description = "Returns statistics on the store: the number of annotations, resources, datasets, keys, annotation data and substores"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns statistics on an annotation store
+async fn get_store_stats(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    storepool.map(&store_id, |store| {
+        match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON]) {
+            Ok(CONTENT_TYPE_JSON) => {
+                let (keys, data) = store.datasets().fold((0, 0), |(keys, data), dataset| {
+                    (
+                        keys + dataset.as_ref().keys_len(),
+                        data + dataset.as_ref().data_len(),
+                    )
+                });
+                Ok(ApiResponse::Json(serde_json::json!({
+                    "annotations": store.annotations_len(),
+                    "resources": store.resources_len(),
+                    "datasets": store.datasets_len(),
+                    "keys": keys,
+                    "data": data,
+                    "substores": store.substores_len(),
+                })))
+            }
+            _ => Err(ApiError::NotAcceptable(
+                "Accept headed could not be satisfied (try application/json)",
+            )),
+        }
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/metadata",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, body = StoreMetadata, description = "Returns the descriptive metadata of the store (JSON), or a DCAT description of the store for use by data catalogues (JSON-LD). Stores without metadata return an empty object."),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns the metadata of an annotation store
+async fn get_metadata(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+    request: Request<Body>,
+) -> Result<ApiResponse, ApiError> {
+    let metadata = storepool.metadata(&store_id)?;
+    match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON, CONTENT_TYPE_JSONLD]) {
+        Ok(CONTENT_TYPE_JSON) => Ok(ApiResponse::Json(
+            serde_json::to_value(metadata)
+                .map_err(|_| ApiError::InternalError("Unable to serialize metadata"))?,
+        )),
+        Ok(CONTENT_TYPE_JSONLD) => Ok(ApiResponse::RawJsonLd(
+            metadata
+                .to_dcat(&store_id, &store_url(&storepool, &store_id), true)
+                .to_string(),
+        )),
+        _ => Err(ApiError::NotAcceptable(
+            "Accept headed could not be satisfied (try application/json or application/ld+json)",
+        )),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/{store_id}/metadata",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    request_body(content_type = "application/json", content = StoreMetadata),
+    responses(
+        (status = 201, description = "Returned when successfully saved. The metadata replaces any earlier metadata, an empty object removes it."),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if a language tag is invalid", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json")
+    )
+)]
+/// Sets the metadata of an annotation store
+async fn put_metadata(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+    Json(metadata): Json<StoreMetadata>,
+) -> Result<ApiResponse, ApiError> {
+    storepool.set_metadata(&store_id, metadata)?;
+    Ok(ApiResponse::Created())
+}
+
+/// Returns the URL of a store
+fn store_url(storepool: &StorePool, store_id: &str) -> String {
+    format!("{}/{}", storepool.baseurl().trim_end_matches('/'), store_id)
+}
+
+#[utoipa::path(
+    get,
+    path = "/_cache",
+    responses(
+        (status = 200, body = BTreeMap<String, usize>, description = "Returns statistics on the query result cache: its `capacity`, the current number of `entries`, its maximum size (`max_bytes`) and current size (`bytes`), and the number of `hits`, `misses`, `evictions` (entries removed to make room) and `invalidations` (entries removed because their store changed)"),
+    )
+)]
+/// Returns statistics on the query result cache
+async fn get_cache_metrics(storepool: State<Arc<StorePool>>) -> Result<ApiResponse, ApiError> {
+    Ok(ApiResponse::Json(
+        serde_json::to_value(storepool.cache().metrics()?)
+            .map_err(|_| ApiError::InternalError("Unable to serialize cache metrics"))?,
+    ))
+}
+
+/// Serves the static assets of the query interface
+async fn get_asset(Path(asset): Path<String>) -> Result<ApiResponse, ApiError> {
+    match ui::asset(&asset) {
+        Some((content_type, data)) => Ok(ApiResponse::Asset(content_type, data)),
+        None => Err(ApiError::NotFound("No such asset")),
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/{store_id}/annotations/{annotation_id}",
@@ -486,6 +1062,7 @@ async fn get_resource_list(
             (apidocs::StamJson = "application/json"),
             (apidocs::WebAnnotation = "application/ld+json"),
             (String = "text/plain"),
+            (String = "text/html"),
         )),
         (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store or annotation does not exist", content_type = "application/json"),
@@ -503,11 +1080,30 @@ async fn get_annotation(
         Some(annotation) => {
             match negotiate_content_type(
                 request.headers(),
-                &[CONTENT_TYPE_JSON, CONTENT_TYPE_JSONLD, CONTENT_TYPE_TEXT],
+                &[
+                    CONTENT_TYPE_JSON,
+                    CONTENT_TYPE_JSONLD,
+                    CONTENT_TYPE_TEXT,
+                    CONTENT_TYPE_HTML,
+                ],
             ) {
                 Ok(CONTENT_TYPE_JSON) => Ok(ApiResponse::RawJson(
                     annotation.as_ref().to_json_string(store)?,
                 )),
+                Ok(CONTENT_TYPE_HTML) => {
+                    let querystring = format!(
+                        "SELECT ANNOTATION ?annotation WHERE ID {}; {{ @IDTAG SELECT OPTIONAL ANNOTATION ?annotations WHERE RELATION ?annotation EMBEDS; }}",
+                        quote_stamql(annotation.id().unwrap_or_default())?
+                    );
+                    let mut annotations: Vec<_> = annotation
+                        .related_text(TextSelectionOperator::embeds())
+                        .annotations()
+                        .collect();
+                    if !annotations.contains(&annotation) {
+                        annotations.insert(0, annotation.clone());
+                    }
+                    html_response(store, &querystring, annotations)
+                }
                 Ok(CONTENT_TYPE_JSONLD) => {
                     if let Ok(webannoconfigs) = storepool.webannoconfigs().read() {
                         if let Some(webannoconfig) = webannoconfigs.get(&store_id) {
@@ -535,13 +1131,21 @@ async fn get_annotation(
     path = "/{store_id}/resources/{resource_id}",
     params(
         ("store_id" = String, Path, description = "The identifier of the store the resource is in"),
-        ("resource_id" = String, Path, description = "The identifier of the resource"),
+        ("resource_id" = String, Path, description = "The identifier of the resource. This may be followed by a (percent-encoded) RFC 5147 fragment identifier like `#char=0,10`, which is only split off if no resource with the full identifier exists."),
+        ("char" = Option<String>, Query, description = "Select a part of the text using an RFC 5147 character range, e.g. `0,10` (as in `#char=0,10`)"),
+        ("line" = Option<String>, Query, description = "Select a part of the text using an RFC 5147 line range, e.g. `0,1` (as in `#line=0,1`) for the first line"),
+        ("exact" = Option<String>, Query, description = "Select a part of the text by quotation, as in a W3C TextQuoteSelector. The first match is returned."),
+        ("prefix" = Option<String>, Query, description = "The text that must immediately precede `exact`, as in a W3C TextQuoteSelector"),
+        ("suffix" = Option<String>, Query, description = "The text that must immediately follow `exact`, as in a W3C TextQuoteSelector"),
+        ("relation" = Option<String>, Query, description = "When selecting a part of the text, also return the annotations that are in the specified relation with it. See the `/{store_id}/resources/{resource_id}/{begin}/{end}` endpoint."),
     ),
     responses(
-        (status = 200, description = "The resource. Several return types are supported via content negotation.",content(
-            (apidocs::StamJson = "application/json"),
+        (status = 200, description = "The resource, or the selected part of it. Several return types are supported via content negotation, JSON is only available when a part of the text is selected.",content(
             (String = "text/plain"),
+            (apidocs::StamJson = "application/json"),
+            (String = "text/html"),
         )),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if an invalid fragment identifier or relation is passed", content_type = "application/json"),
         (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
         (status = 404, body = apidocs::StamError, description = "Returned when a STAM error occurs", content_type = "application/json"),
@@ -550,17 +1154,74 @@ async fn get_annotation(
 /// Returns a text resource given its identifier
 async fn get_resource(
     Path((store_id, resource_id)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
     storepool: State<Arc<StorePool>>,
     request: Request<Body>,
 ) -> Result<ApiResponse, ApiError> {
-    storepool.map(&store_id, |store| match store.resource(resource_id) {
-        None => Err(ApiError::NotFound("No such resource")),
-        Some(resource) => match negotiate_content_type(request.headers(), &[CONTENT_TYPE_TEXT]) {
-            Ok(CONTENT_TYPE_TEXT) => Ok(ApiResponse::Text(resource.text().to_string())),
-            _ => Err(ApiError::NotAcceptable(
-                "Accept headed could not be satisfied (try application/json)",
-            )),
-        },
+    let operator = params
+        .get("relation")
+        .map(|relation| parse_relation(relation))
+        .transpose()?;
+    storepool.map(&store_id, |store| {
+        //an RFC 5147 fragment identifier may also be passed as part of the (percent-encoded) resource ID,
+        //resource IDs may contain a '#' themselves so we only split it off if there is no resource with the full ID
+        let (resource, fragment) = match store.resource(resource_id.as_str()) {
+            Some(resource) => (Some(resource), None),
+            None => match resource_id.rsplit_once('#') {
+                Some((resource_id, fragment)) => (store.resource(resource_id), Some(fragment)),
+                None => (None, None),
+            },
+        };
+        let fragment = fragment.map(|fragment| fragment.to_string()).or_else(|| {
+            params
+                .get("char")
+                .map(|range| format!("char={}", range))
+                .or_else(|| params.get("line").map(|range| format!("line={}", range)))
+        });
+        let Some(resource) = resource else {
+            return Err(ApiError::NotFound("No such resource"));
+        };
+        let offset = if let Some(fragment) = fragment.as_ref() {
+            Some(rfc5147_to_offset(resource.text(), fragment)?)
+        } else if let Some(exact) = params.get("exact") {
+            Some(textquote_to_offset(
+                resource.text(),
+                exact,
+                params.get("prefix").map(|s| s.as_str()),
+                params.get("suffix").map(|s| s.as_str()),
+            )?)
+        } else {
+            None
+        };
+        if let Some(offset) = offset {
+            let textselection = resource.textselection(&offset)?;
+            textselection_response(
+                store,
+                &textselection,
+                operator,
+                negotiate_content_type(
+                    request.headers(),
+                    &[CONTENT_TYPE_TEXT, CONTENT_TYPE_JSON, CONTENT_TYPE_HTML],
+                ),
+            )
+        } else {
+            match negotiate_content_type(
+                request.headers(),
+                &[CONTENT_TYPE_TEXT, CONTENT_TYPE_HTML],
+            ) {
+                Ok(CONTENT_TYPE_TEXT) => Ok(ApiResponse::Text(resource.text().to_string())),
+                Ok(CONTENT_TYPE_HTML) => {
+                    let querystring = format!(
+                        "SELECT RESOURCE ?resource WHERE ID {}; {{ @IDTAG SELECT OPTIONAL ANNOTATION ?annotations WHERE RESOURCE ?resource; }}",
+                        quote_stamql(resource.id().unwrap_or_default())?
+                    );
+                    html_response(store, &querystring, resource.annotations().collect())
+                }
+                _ => Err(ApiError::NotAcceptable(
+                    "Accept headed could not be satisfied (try text/plain)",
+                )),
+            }
+        }
     })
 }
 
@@ -570,40 +1231,462 @@ async fn get_resource(
     params(
         ("store_id" = String, Path, description = "The identifier of the store the resource is in"),
         ("resource_id" = String, Path, description = "The identifier of the resource"),
-        ("begin" = isize, Path, description = "An integer indicating the begin offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors."),
-        ("end" = isize, Path, description = "An integer indicating the non-inclusive end offset in unicode points (0-indexed). This may be a negative integer for end-aligned cursors. `-0` is a special value in this context, which means until the very end."),
+        ("begin" = String, Path, description = "The begin offset (0-indexed), by default an integer in unicode points. This may be a negative integer for end-aligned cursors. See `unit` for alternatives."),
+        ("end" = String, Path, description = "The non-inclusive end offset (0-indexed), by default an integer in unicode points. This may be a negative integer for end-aligned cursors. `-0` is a special value in this context, which means until the very end. See `unit` for alternatives."),
+        ("unit" = Option<String>, Query, description = "The unit in which `begin` and `end` are expressed: `unicode` (unicode points, default), `bytes` (UTF-8 bytes), `utf16` (UTF-16 code units, as used by JavaScript) or `line` (`line:column`, both 1-indexed, column in unicode points). Negative cursors are only supported for `unicode`. The JSON response always reports the offsets in all units."),
+        ("relation" = Option<String>, Query, description = "Also return the annotations that are in the specified relation with this text selection. Values: `overlaps` (annotations whose text overlaps with the selection), `embeds` (annotations whose text is embedded in the selection), `embedded` (annotations whose text embeds the selection), `equals` (annotations on exactly this text). Only applies to JSON output."),
     ),
     responses(
-        (status = 200, description = "The resource. Several return types are supported via content negotation.",content(
+        (status = 200, description = "The text selection. Several return types are supported via content negotation. If `relation` is set, the JSON response is an object with a `textselection` and an `annotations` key.",content(
             (apidocs::StamJson = "application/json"),
+            (apidocs::TextSelectionWithAnnotations = "application/json"),
             (String = "text/plain"),
+            (String = "text/html"),
         )),
         (status = 406, body = apidocs::ApiError, description = "This is returned if the requested content-type (Accept) could not be delivered", content_type = "application/json"),
         (status = 404, body = apidocs::ApiError, description = "An ApiError with name 'NotFound` is returned if the store or resource does not exist", content_type = "application/json"),
         (status = 404, body = apidocs::StamError, description = "Returned when a STAM error occurs, such as invalid offsets.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if an invalid relation is passed", content_type = "application/json"),
     )
 )]
 /// Returns an text selection given a resource identifier and an offset
 async fn get_textselection(
     Path((store_id, resource_id, begin, end)): Path<(String, String, String, String)>,
+    Query(params): Query<HashMap<String, String>>,
     storepool: State<Arc<StorePool>>,
     request: Request<Body>,
 ) -> Result<ApiResponse, ApiError> {
-    let offset = Offset::new(begin.as_str().try_into()?, end.as_str().try_into()?);
+    let unit: OffsetUnit = params
+        .get("unit")
+        .map(|unit| unit.as_str().try_into())
+        .transpose()?
+        .unwrap_or(OffsetUnit::Unicode);
+    let operator = params
+        .get("relation")
+        .map(|relation| parse_relation(relation))
+        .transpose()?;
     storepool.map(&store_id, |store| match store.resource(resource_id) {
         None => Err(ApiError::NotFound("No such resource")),
         Some(resource) => {
+            let offset = unit.to_offset(resource.text(), &begin, &end)?;
             let textselection = resource.textselection(&offset)?;
-            match negotiate_content_type(request.headers(), &[CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT])
-            {
-                Ok(CONTENT_TYPE_JSON) => Ok(ApiResponse::RawJson(textselection.to_json_string()?)),
-                Ok(CONTENT_TYPE_TEXT) => Ok(ApiResponse::Text(textselection.text().to_string())),
-                _ => Err(ApiError::NotAcceptable(
-                    "Accept headed could not be satisfied (try application/json)",
-                )),
-            }
+            textselection_response(
+                store,
+                &textselection,
+                operator,
+                negotiate_content_type(
+                    request.headers(),
+                    &[CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT, CONTENT_TYPE_HTML],
+                ),
+            )
+        }
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_batch",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    request_body(content_type = "application/json", content = [BatchRequest], description = "A list of requests, each with a `type`: `annotation` (with an `id`), `resource` (with an `id`, `begin` and `end`, and optionally a `unit` and `relation` as for `/{store_id}/resources/{resource_id}/{begin}/{end}`) or `query` (with a read-only `query` and optionally `use`)"),
+    responses(
+        (status = 200, body = [apidocs::StamJson], description = "Returns one result per request, in the same order, as it would be returned in JSON by the corresponding endpoint. A request that fails yields an error object (ApiError or StamError) in its place, without affecting the other requests.", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when the batch exceeds the time limit", content_type = "application/json")
+    )
+)]
+/// Executes several requests on an annotation store at once. All requests are executed on the same state of the store.
+async fn post_batch(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+    Json(requests): Json<Vec<BatchRequest>>,
+) -> Result<ApiResponse, ApiError> {
+    let storepool = storepool.0;
+    let budget = QueryBudget::new(storepool.query_timeout());
+    let _guard = budget.cancel_on_drop();
+    tokio::task::spawn_blocking(move || batch::execute(&store_id, requests, &storepool, &budget))
+        .await
+        .map_err(|_| ApiError::InternalError("Batch execution failed"))?
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_transaction",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("save" = Option<bool>, Query, description = "Save the store before returning, rather than leaving that to the autosave"),
+    ),
+    request_body(content_type = "application/json", content = [String], description = "A list of STAMQL queries, usually mutating ones (`ADD`/`DELETE`), executed in order. Later queries see the changes made by earlier ones."),
+    responses(
+        (status = 200, body = [[BTreeMap<String,apidocs::StamJson>]], description = "All queries succeeded. Returns the results of each query, in the same order, as a regular query would return them in JSON.", content_type = "application/json"),
+        (status = 404, body = apidocs::StamError, description = "Returned when a query is invalid or fails, nothing is changed in that case", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json"),
+        (status = 504, body = apidocs::ApiError, description = "Returned with name `Timeout` when the transaction exceeds the time limit, nothing is changed in that case", content_type = "application/json")
+    )
+)]
+/// Executes several queries on an annotation store as a single transaction: either all of them succeed, or none of their changes are applied.
+async fn post_transaction(
+    Path(store_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+    headers: HeaderMap<HeaderValue>,
+    Json(queries): Json<Vec<String>>,
+) -> Result<ApiResponse, ApiError> {
+    let storepool = storepool.0;
+    let user = remote_user(&headers);
+    let save = save_requested(params.get("save").map(|s| s.as_str()))?;
+    let budget = QueryBudget::new(storepool.query_timeout());
+    let _guard = budget.cancel_on_drop();
+    tokio::task::spawn_blocking(move || {
+        let response =
+            transaction::execute(&store_id, queries, user.as_deref(), &storepool, &budget)?;
+        if save {
+            storepool.save(&store_id)?;
+        }
+        Ok(response)
+    })
+    .await
+    .map_err(|_| ApiError::InternalError("Transaction execution failed"))?
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/history",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, body = [Revision], description = "Returns all recorded revisions of the store, oldest first. Revision 0 is the state of the store when recording started.", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist or history is not enabled", content_type = "application/json"),
+    )
+)]
+/// Returns the change history of an annotation store
+async fn get_history(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    let revisions = storepool.revisions(&store_id)?;
+    Ok(ApiResponse::Json(serde_json::to_value(revisions).map_err(
+        |_| ApiError::InternalError("Unable to serialize history"),
+    )?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/history/{revision}/revert",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("revision" = usize, Path, description = "The revision to revert to"),
+        ("save" = Option<bool>, Query, description = "Save the store before returning, rather than leaving that to the autosave"),
+    ),
+    responses(
+        (status = 200, body = Revision, description = "Returns the new revision that records the revert, the history itself is never rewritten", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store or revision does not exist, or history is not enabled", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json")
+    )
+)]
+/// Reverts an annotation store to the state of an earlier revision
+async fn post_revert(
+    Path((store_id, revision)): Path<(String, usize)>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+    headers: HeaderMap<HeaderValue>,
+) -> Result<ApiResponse, ApiError> {
+    let save = save_requested(params.get("save").map(|s| s.as_str()))?;
+    let revision = tokio::task::spawn_blocking(move || {
+        let revision = storepool.revert(&store_id, revision, remote_user(&headers).as_deref())?;
+        if save {
+            storepool.save(&store_id)?;
+        }
+        Ok::<_, ApiError>(revision)
+    })
+    .await
+    .map_err(|_| ApiError::InternalError("Revert failed"))??;
+    Ok(ApiResponse::Json(serde_json::to_value(revision).map_err(
+        |_| ApiError::InternalError("Unable to serialize history"),
+    )?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/{store_id}/backups",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, body = [Backup], description = "Returns all backups of earlier versions of the store, oldest first", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+    )
+)]
+/// Returns the backups of an annotation store
+async fn get_backups(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    let backups = storepool.list_backups(&store_id)?;
+    Ok(ApiResponse::Json(serde_json::to_value(backups).map_err(
+        |_| ApiError::InternalError("Unable to serialize backups"),
+    )?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/backups/{backup}/restore",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("backup" = u64, Path, description = "The backup to restore"),
+        ("save" = Option<bool>, Query, description = "Save the store before returning, rather than leaving that to the autosave"),
+    ),
+    responses(
+        (status = 200, description = "Returned when the store was restored. The version it replaces becomes a backup in turn when the store is saved."),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store or backup does not exist", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes, see `/{store_id}/_reload`", content_type = "application/json")
+    )
+)]
+/// Restores an annotation store from a backup
+async fn post_restore_backup(
+    Path((store_id, backup)): Path<(String, u64)>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+    headers: HeaderMap<HeaderValue>,
+) -> Result<ApiResponse, ApiError> {
+    let save = save_requested(params.get("save").map(|s| s.as_str()))?;
+    tokio::task::spawn_blocking(move || {
+        storepool.restore_backup(&store_id, backup, remote_user(&headers).as_deref())?;
+        if save {
+            storepool.save(&store_id)?;
+        }
+        Ok::<_, ApiError>(())
+    })
+    .await
+    .map_err(|_| ApiError::InternalError("Restore failed"))??;
+    Ok(ApiResponse::Text("restored".to_string()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_reload",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+    ),
+    responses(
+        (status = 200, description = "Returned when the store was loaded from disk again. Unsaved changes are kept as a backup, they can be restored via `/{store_id}/backups/{backup}/restore`."),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist", content_type = "application/json"),
+    )
+)]
+/// Discards an annotation store as it is in memory and loads it from disk again. This resolves a conflict, which arises when the store file is changed on disk while the store has unsaved changes.
+async fn post_reload(
+    Path(store_id): Path<String>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    tokio::task::spawn_blocking(move || storepool.reload(&store_id))
+        .await
+        .map_err(|_| ApiError::InternalError("Reload failed"))??;
+    Ok(ApiResponse::Text("reloaded".to_string()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_copy",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("to" = String, Query, description = "The identifier of the new store, may contain a path to copy it to a subdirectory"),
+    ),
+    responses(
+        (status = 201, description = "Returned when the store was copied. The copy includes any unsaved changes, the metadata and the saved queries of the store, but not its history or backups. Stand-off files are shared with copies in the same directory and copied for copies elsewhere."),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if the new identifier is missing, or if the store refers to files outside its own directory and is copied elsewhere", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only or the new store already exists", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist or the new identifier is invalid", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if a different file already exists in place of a stand-off file of the copy", content_type = "application/json")
+    )
+)]
+/// Copies an annotation store to a new store
+async fn post_copy(
+    Path(store_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    let to = params
+        .get("to")
+        .cloned()
+        .ok_or(ApiError::InvalidArgument("The to parameter is required"))?;
+    tokio::task::spawn_blocking(move || storepool.copy(&store_id, &to))
+        .await
+        .map_err(|_| ApiError::InternalError("Copy failed"))??;
+    Ok(ApiResponse::Created())
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_rename",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store"),
+        ("to" = String, Query, description = "The new identifier of the store, may contain a path to move it to a subdirectory"),
+    ),
+    responses(
+        (status = 200, description = "Returned when the store was renamed. Unsaved changes, metadata, saved queries, history and backups move along. Stand-off files are copied rather than moved when the store moves to another directory, as other stores may share them."),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if the new identifier is missing, or if the store refers to files outside its own directory and is moved elsewhere", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only or a store with the new identifier already exists", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if the store does not exist or the new identifier is invalid", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if the store was changed on disk while it had unsaved changes (see `/{store_id}/_reload`), or if a different file already exists in place of a stand-off file", content_type = "application/json")
+    )
+)]
+/// Renames an annotation store
+async fn post_rename(
+    Path(store_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+) -> Result<ApiResponse, ApiError> {
+    let to = params
+        .get("to")
+        .cloned()
+        .ok_or(ApiError::InvalidArgument("The to parameter is required"))?;
+    tokio::task::spawn_blocking(move || storepool.rename(&store_id, &to))
+        .await
+        .map_err(|_| ApiError::InternalError("Rename failed"))??;
+    Ok(ApiResponse::Text("renamed".to_string()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/{store_id}/_merge",
+    params(
+        ("store_id" = String, Path, description = "The identifier of the store to merge into"),
+        ("from" = String, Query, description = "The identifier of the store to merge, this store itself is left as it is"),
+        ("collisions" = Option<String>, Query, description = "What to do with items whose identifier is taken by a different item in the target store: `fail` to abort the merge (the default), `skip` to leave them out along with everything that depends on them, or `rename` to add them under a new identifier (`{id}.1`, `{id}.2`, etc)"),
+        ("save" = Option<bool>, Query, description = "Save the store before returning, rather than leaving that to the autosave"),
+    ),
+    responses(
+        (status = 200, body = MergeReport, description = "Returned when the store was merged, with a report of what was added, matched, renamed and skipped. Resources are aligned by identifier, or else by their text. Datasets and keys are merged by identifier, data by identifier or else by key and value. Annotations that already exist with the same target and data are not added again.", content_type = "application/json"),
+        (status = 400, body = apidocs::ApiError, description = "Returned with name `InvalidArgument` if the from parameter is missing, the collisions parameter is invalid, a store is merged into itself, or the source store has resources or datasets without an identifier", content_type = "application/json"),
+        (status = 403, body = apidocs::ApiError, description = "Returned with name `PermissionDenied` when the service is configured as read-only", content_type = "application/json"),
+        (status = 404, body = apidocs::ApiError, description = "Returned with name `NotFound` if either store does not exist", content_type = "application/json"),
+        (status = 409, body = apidocs::ApiError, description = "Returned with name `Conflict` if an identifier is taken and collisions is `fail` (nothing is changed in that case), or if the store was changed on disk while it had unsaved changes (see `/{store_id}/_reload`)", content_type = "application/json")
+    )
+)]
+/// Merges the resources, datasets and annotations of another store into an annotation store
+async fn post_merge(
+    Path(store_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    storepool: State<Arc<StorePool>>,
+    headers: HeaderMap<HeaderValue>,
+) -> Result<ApiResponse, ApiError> {
+    let storepool = storepool.0;
+    let user = remote_user(&headers);
+    let source = params
+        .get("from")
+        .cloned()
+        .ok_or(ApiError::InvalidArgument("The from parameter is required"))?;
+    let collisions: Collisions = params
+        .get("collisions")
+        .map(|s| s.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let save = save_requested(params.get("save").map(|s| s.as_str()))?;
+    let report = tokio::task::spawn_blocking(move || {
+        let report = storepool.merge(&store_id, &source, collisions, user.as_deref())?;
+        if save {
+            storepool.save(&store_id)?;
         }
+        Ok::<_, ApiError>(report)
     })
+    .await
+    .map_err(|_| ApiError::InternalError("Merge failed"))??;
+    serde_json::to_value(report)
+        .map(ApiResponse::Json)
+        .map_err(|_| ApiError::InternalError("Unable to serialize merge report"))
+}
+
+/// Returns a text selection in the negotiated content type, optionally along with all annotations that are in the given relation with it
+fn textselection_response(
+    store: &AnnotationStore,
+    textselection: &ResultTextSelection,
+    operator: Option<TextSelectionOperator>,
+    content_type: Result<&'static str, ApiError>,
+) -> Result<ApiResponse, ApiError> {
+    match content_type {
+        Ok(CONTENT_TYPE_JSON) => {
+            if let Some(operator) = operator {
+                let mut annotations = Vec::new();
+                for annotation in textselection.related_text(operator).annotations() {
+                    annotations.push(annotation.as_ref().to_json_value(store)?);
+                }
+                Ok(ApiResponse::Json(serde_json::json!({
+                    "textselection": textselection_to_json_value(textselection)?,
+                    "annotations": annotations,
+                })))
+            } else {
+                Ok(ApiResponse::Json(textselection_to_json_value(
+                    textselection,
+                )?))
+            }
+        }
+        Ok(CONTENT_TYPE_TEXT) => Ok(ApiResponse::Text(textselection.text().to_string())),
+        Ok(CONTENT_TYPE_HTML) => {
+            let querystring = format!(
+                "SELECT TEXT ?text WHERE RESOURCE {} OFFSET {} {}; {{ @IDTAG SELECT OPTIONAL ANNOTATION ?annotations WHERE RELATION ?text EMBEDS; }}",
+                quote_stamql(textselection.resource().id().unwrap_or_default())?,
+                textselection.begin(),
+                textselection.end()
+            );
+            html_response(
+                store,
+                &querystring,
+                textselection
+                    .related_text(TextSelectionOperator::embeds())
+                    .annotations()
+                    .collect(),
+            )
+        }
+        _ => Err(ApiError::NotAcceptable(
+            "Accept headed could not be satisfied (try application/json)",
+        )),
+    }
+}
+
+/// Renders an HTML visualisation of a (generated) STAMQL query, with a table of the given annotations and their data underneath
+fn html_response<'store>(
+    store: &'store AnnotationStore,
+    querystring: &str,
+    annotations: Vec<ResultItem<'store, Annotation>>,
+) -> Result<ApiResponse, ApiError> {
+    let (query, _) = parse_query(querystring)?;
+    let mut footer = String::from("<table class=\"annotations\">\n<tr><th>Annotation</th><th>Set</th><th>Key</th><th>Value</th></tr>\n");
+    for annotation in annotations {
+        let id = html_escape(annotation.id().unwrap_or_default());
+        for data in annotation.data() {
+            footer += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                id,
+                html_escape(data.set().id().unwrap_or_default()),
+                html_escape(data.key().id().unwrap_or_default()),
+                html_escape(&data.value().to_string())
+            );
+        }
+    }
+    footer += "</table>\n</body></html>";
+    let htmlwriter = HtmlWriter::new(store, query, None)
+        .map_err(ApiError::CustomNotFound)?
+        .with_footer(Some(&footer));
+    Ok(ApiResponse::Html(htmlwriter.to_string()))
+}
+
+/// Parses the name of a relation between text selections into a [`TextSelectionOperator`]
+fn parse_relation(relation: &str) -> Result<TextSelectionOperator, ApiError> {
+    match relation {
+        "overlaps" => Ok(TextSelectionOperator::overlaps()),
+        "embeds" => Ok(TextSelectionOperator::embeds()),
+        "embedded" => Ok(TextSelectionOperator::embedded()),
+        "equals" => Ok(TextSelectionOperator::equals()),
+        _ => Err(ApiError::InvalidArgument(
+            "Invalid relation (must be one of: overlaps, embeds, embedded, equals)",
+        )),
+    }
 }
 
 fn negotiate_content_type(
@@ -622,13 +1705,12 @@ fn negotiate_content_type(
         {
             let accept_type = accept_type.split(";").next().unwrap();
             for offer_type in offer_types.iter() {
-                if *offer_type == accept_type || accept_type == "*/*" {
-                    if match_accept_index.is_none()
-                        || (match_accept_index.is_some() && match_accept_index.unwrap() > i)
-                    {
-                        match_accept_index = Some(i);
-                        matching_offer = Some(*offer_type);
-                    }
+                if (*offer_type == accept_type || accept_type == "*/*")
+                    && (match_accept_index.is_none()
+                        || (match_accept_index.is_some() && match_accept_index.unwrap() > i))
+                {
+                    match_accept_index = Some(i);
+                    matching_offer = Some(*offer_type);
                 }
             }
         }
@@ -642,47 +1724,304 @@ fn negotiate_content_type(
     }
 }
 
-fn run_query(
+/// Serializes the annotations in query results as a W3C Web Annotation `AnnotationCollection`, with a single `AnnotationPage` holding all annotations.
+/// The annotations are taken from the specified variable, or from the first variable if none is specified.
+fn webannotation_collection(
+    queryiter: QueryIter,
+    use_variable: Option<&str>,
+    webannoconfig: &WebAnnoConfig,
+    budget: &QueryBudget,
+) -> Result<serde_json::Value, ApiError> {
+    let mut items = Vec::new();
+    for resultitems in queryiter {
+        budget.check()?;
+        match resultitems.get_by_name_or_first(use_variable)? {
+            QueryResultItem::Annotation(annotation) => {
+                let webannotation = annotation.to_webannotation(webannoconfig);
+                if webannotation.is_empty() {
+                    //annotations on data or keys can not be represented as web annotations
+                    continue;
+                }
+                let mut webannotation: serde_json::Value = serde_json::from_str(&webannotation)
+                    .map_err(|_| ApiError::InternalError("Invalid web annotation produced"))?;
+                if let serde_json::Value::Object(map) = &mut webannotation {
+                    //the context is already declared on the collection
+                    map.remove("@context");
+                }
+                items.push(webannotation);
+            }
+            _ => {
+                return Err(ApiError::NotAcceptable(
+                    "JSON-LD output is only available if the (selected) variable is an annotation (try application/json instead)",
+                ))
+            }
+        }
+    }
+    let context: serde_json::Value = serde_json::from_str(&webannoconfig.serialize_context())
+        .map_err(|_| ApiError::InternalError("Invalid JSON-LD context"))?;
+    Ok(serde_json::json!({
+        "@context": context,
+        "type": "AnnotationCollection",
+        "total": items.len(),
+        "first": {
+            "type": "AnnotationPage",
+            "startIndex": 0,
+            "items": items,
+        }
+    }))
+}
+
+/// Options that determine how query results are returned
+struct ResultOptions {
+    /// Only return this variable
+    use_variable: Option<String>,
+    /// Columns for tabular output
+    columns: Option<Vec<tabular::Column>>,
+    /// Delimiter between results for plain text output
+    delimiter: String,
+    /// Delimiter between variables for plain text output
+    vardelimiter: String,
+}
+
+impl ResultOptions {
+    fn new(
+        use_variable: Option<&str>,
+        columns: Option<&str>,
+        delimiter: Option<&str>,
+        vardelimiter: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        Ok(Self {
+            use_variable: use_variable.map(|s| s.to_string()),
+            columns: columns.map(tabular::parse_columns).transpose()?,
+            delimiter: delimiter.map(unescape).unwrap_or("\n".to_string()),
+            vardelimiter: vardelimiter.map(unescape).unwrap_or("\t".to_string()),
+        })
+    }
+}
+
+/// Rewrites requests for stores in subdirectories (`/project/subcorpus/x/...`) so the store identifier is a single path segment
+fn resolve_nested_store(storepool: &StorePool, mut request: Request<Body>) -> Request<Body> {
+    if let Some(path) = storepool.resolve_nested_path(request.uri().path()) {
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = request.uri().clone().into_parts();
+        if let Ok(path_and_query) = path_and_query.parse() {
+            parts.path_and_query = Some(path_and_query);
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *request.uri_mut() = uri;
+            }
+        }
+    }
+    request
+}
+
+/// Returns the user on whose behalf a request is made, if known
+fn remote_user(headers: &HeaderMap<HeaderValue>) -> Option<String> {
+    headers
+        .get(USER_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Interprets the `save` parameter of mutating requests, which requests the store to be saved before returning
+fn save_requested(value: Option<&str>) -> Result<bool, ApiError> {
+    Ok(bool_parameter(value, "The save parameter must be true or false")?.unwrap_or(false))
+}
+
+/// Interprets an optional boolean parameter, `error` is returned for anything other than `true` or `false`
+fn bool_parameter(value: Option<&str>, error: &'static str) -> Result<Option<bool>, ApiError> {
+    match value {
+        None => Ok(None),
+        Some("false") => Ok(Some(false)),
+        Some("true") => Ok(Some(true)),
+        Some(_) => Err(ApiError::InvalidArgument(error)),
+    }
+}
+
+/// Interprets the escape sequences `\n`, `\t` and `\\` in a delimiter
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Runs a query on a blocking thread, within the configured time budget.
+/// If the client disconnects, this future is dropped and the query is cancelled.
+async fn run_query(
+    store_id: String,
+    querystring: String,
+    options: ResultOptions,
+    save: bool,
+    storepool: Arc<StorePool>,
+    headers: HeaderMap<HeaderValue>,
+) -> Result<ApiResponse, ApiError> {
+    let budget = QueryBudget::new(storepool.query_timeout());
+    let _guard = budget.cancel_on_drop();
+    tokio::task::spawn_blocking(move || {
+        run_query_blocking(
+            &store_id,
+            &querystring,
+            options,
+            save,
+            &storepool,
+            &headers,
+            &budget,
+        )
+    })
+    .await
+    .map_err(|_| ApiError::InternalError("Query execution failed"))?
+}
+
+fn run_query_blocking(
     store_id: &str,
     querystring: &str,
-    use_variable: Option<&str>,
-    storepool: State<Arc<StorePool>>,
+    options: ResultOptions,
+    save: bool,
+    storepool: &StorePool,
     headers: &HeaderMap<HeaderValue>,
+    budget: &QueryBudget,
 ) -> Result<ApiResponse, ApiError> {
-    let (query, _) = stam::Query::parse(querystring)?;
-    if let Ok(CONTENT_TYPE_HTML) = negotiate_content_type(
+    let (query, _) = parse_query(querystring)?;
+    let content_type = negotiate_content_type(
         headers,
-        &[CONTENT_TYPE_JSON, CONTENT_TYPE_HTML, CONTENT_TYPE_TEXT],
-    ) {
-        storepool.map(&store_id, |store| {
-            let htmlwriter = HtmlWriter::new(&store, query, use_variable)
-                .map_err(|e| ApiError::CustomNotFound(e))?;
-            Ok(ApiResponse::Html(htmlwriter.to_string()))
-        })
-    } else if query.querytype().readonly() {
-        storepool.map(&store_id, |store| match store.query(query) {
-            Err(err) => Err(ApiError::StamError(err)),
-            Ok(queryiter) => query_results(queryiter, headers, use_variable),
+        &[
+            CONTENT_TYPE_JSON,
+            CONTENT_TYPE_HTML,
+            CONTENT_TYPE_JSONLD,
+            CONTENT_TYPE_TEXT,
+            CONTENT_TYPE_TSV,
+            CONTENT_TYPE_CSV,
+        ],
+    )?;
+    if query.querytype().readonly() {
+        storepool.map(store_id, |store| {
+            //results of read-only queries are cached, the cache is invalidated whenever the store changes
+            let key = CacheKey {
+                store_id: store_id.to_string(),
+                querystring: querystring.to_string(),
+                use_variable: options.use_variable.clone(),
+                content_type,
+                columns: options.columns.clone(),
+                delimiter: options.delimiter.clone(),
+                vardelimiter: options.vardelimiter.clone(),
+            };
+            if let Some(response) = storepool.cache().get(&key) {
+                return Ok(response);
+            }
+            let response = if content_type == CONTENT_TYPE_HTML {
+                //the visualisation is rendered in one go and can not be interrupted
+                budget.check()?;
+                let htmlwriter = HtmlWriter::new(store, query, options.use_variable.as_deref())
+                    .map_err(ApiError::CustomNotFound)?;
+                ApiResponse::Html(htmlwriter.to_string())
+            } else {
+                let queryiter = store.query(query).map_err(ApiError::StamError)?;
+                query_results(
+                    queryiter,
+                    content_type,
+                    options,
+                    budget,
+                    store_id,
+                    storepool,
+                )?
+            };
+            storepool.cache().insert(key, response.clone());
+            Ok(response)
         })
+    } else if content_type == CONTENT_TYPE_HTML {
+        Err(ApiError::NotAcceptable(
+            "HTML visualisation is only available for read-only queries",
+        ))
     } else {
-        storepool.map_mut(&store_id, |store| match store.query_mut(query) {
-            Err(err) => Err(ApiError::StamError(err)),
-            Ok(queryiter) => query_results(queryiter, headers, use_variable),
-        })
+        let response =
+            storepool.map_mut_recorded(store_id, remote_user(headers).as_deref(), |store| {
+                //the time limit may have been exceeded while waiting for the write lock, the change itself can not be interrupted
+                budget.check()?;
+                let queryiter = store.query_mut(query)?;
+                //the change is recorded even if its results can't be returned in the requested way,
+                //once it is applied we return its results regardless of the time limit, a timeout would suggest the change failed
+                let response = query_results(
+                    queryiter,
+                    content_type,
+                    options,
+                    &QueryBudget::new(None),
+                    store_id,
+                    storepool,
+                );
+                Ok((
+                    response,
+                    Change::Query {
+                        queries: vec![querystring.to_string()],
+                    },
+                ))
+            })?;
+        if save {
+            storepool.save(store_id)?;
+        }
+        response
     }
 }
 
 fn query_results(
     queryiter: QueryIter,
-    headers: &HeaderMap<HeaderValue>,
-    use_variable: Option<&str>,
+    content_type: &str,
+    options: ResultOptions,
+    budget: &QueryBudget,
+    store_id: &str,
+    storepool: &StorePool,
 ) -> Result<ApiResponse, ApiError> {
-    match negotiate_content_type(headers, &[CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT]) {
-        Ok(CONTENT_TYPE_JSON) => {
-            if let Some(use_variable) = use_variable {
+    match content_type {
+        CONTENT_TYPE_JSONLD => {
+            if let Ok(webannoconfigs) = storepool.webannoconfigs().read() {
+                if let Some(webannoconfig) = webannoconfigs.get(store_id) {
+                    Ok(ApiResponse::RawJsonLd(
+                        webannotation_collection(
+                            queryiter,
+                            options.use_variable.as_deref(),
+                            webannoconfig,
+                            budget,
+                        )?
+                        .to_string(),
+                    ))
+                } else {
+                    Err(ApiError::InternalError("Webannoconfig must exist"))
+                }
+            } else {
+                Err(ApiError::InternalError("Webannoconfigs lock poisoned"))
+            }
+        }
+        CONTENT_TYPE_TSV => Ok(ApiResponse::Tsv(tabular::write_table(
+            queryiter,
+            options.columns,
+            '\t',
+            budget,
+        )?)),
+        CONTENT_TYPE_CSV => Ok(ApiResponse::Csv(tabular::write_table(
+            queryiter,
+            options.columns,
+            ',',
+            budget,
+        )?)),
+        CONTENT_TYPE_JSON => {
+            if let Some(use_variable) = options.use_variable.as_deref() {
                 //output only one variable
                 let mut ser_results = Vec::new();
                 for resultitems in queryiter {
+                    budget.check()?;
                     if let Ok(result) = resultitems.get_by_name(use_variable) {
                         ser_results.push(result.to_json_value()?);
                     }
@@ -692,6 +2031,7 @@ fn query_results(
                 //output all variables
                 let mut ser_results = Vec::new();
                 for resultitems in queryiter {
+                    budget.check()?;
                     let mut responsemap = BTreeMap::new();
                     for (i, (result, name)) in
                         resultitems.iter().zip(resultitems.names()).enumerate()
@@ -706,20 +2046,40 @@ fn query_results(
                 Ok(ApiResponse::JsonMap(ser_results))
             }
         }
-        Ok(CONTENT_TYPE_TEXT) => {
+        CONTENT_TYPE_TEXT => {
+            let mut text = String::new();
+            let mut found = false;
             for (i, resultitems) in queryiter.enumerate() {
+                found = true;
+                budget.check()?;
                 if i > 0 {
-                    return Err(ApiError::NotAcceptable(
-                        "Plain text can not be returned for queries with multiple results (try application/json instead)",
-                    ));
+                    text += &options.delimiter;
                 }
-                if let Ok(result) = resultitems.get_by_name_or_first(use_variable) {
-                    return Ok(ApiResponse::Text(result.text(Some("\t"))?.to_string()));
-                } else {
-                    return Err(ApiError::NotFound("No results found"));
+                let results: Vec<&QueryResultItem> =
+                    if let Some(use_variable) = options.use_variable.as_deref() {
+                        //output only one variable
+                        vec![resultitems.get_by_name(use_variable).map_err(|_| {
+                            ApiError::NotFound("The variable to use does not occur in the query")
+                        })?]
+                    } else {
+                        //output all variables
+                        resultitems.iter().collect()
+                    };
+                for (j, result) in results.into_iter().enumerate() {
+                    if j > 0 {
+                        text += &options.vardelimiter;
+                    }
+                    //items without text (such as data) are output as empty strings, items with multiple parts of text are joined by the variable delimiter
+                    if let Ok(resulttext) = result.text(Some(&options.vardelimiter)) {
+                        text += &resulttext;
+                    }
                 }
             }
-            Err(ApiError::NotFound("No results found"))
+            if found {
+                Ok(ApiResponse::Text(text))
+            } else {
+                Err(ApiError::NotFound("No results found"))
+            }
         }
         _ => Err(ApiError::NotAcceptable(
             "Requested accept type can not be accommodated (try application/json instead)",
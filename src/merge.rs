@@ -0,0 +1,1074 @@
+use crate::common::ApiError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use stam::{
+    Annotation, AnnotationBuilder, AnnotationDataBuilder, AnnotationDataSet,
+    AnnotationDataSetBuilder, AnnotationHandle, AnnotationStore, DataKey, DataValue, ResultItem,
+    Storable, StoreFor, Text, TextResourceBuilder,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+/// What to do with an item of the source store whose identifier is taken by a different item in the target store
+pub enum Collisions {
+    /// Abort the merge, nothing is changed
+    #[default]
+    Fail,
+    /// Leave the item out, along with everything that depends on it
+    Skip,
+    /// Add the item under a new identifier
+    Rename,
+}
+
+impl FromStr for Collisions {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, ApiError> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "skip" => Ok(Self::Skip),
+            "rename" => Ok(Self::Rename),
+            _ => Err(ApiError::InvalidArgument(
+                "The collisions parameter must be rename, skip or fail",
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemType {
+    Resource,
+    Data,
+    Annotation,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default)]
+/// The number of items of one type in the source store, by what happened to them
+pub struct MergeCount {
+    /// Items that were added to the target store
+    pub added: usize,
+    /// Items that already existed in the target store
+    pub matched: usize,
+    /// Items that were added under a new identifier
+    pub renamed: usize,
+    /// Items that were left out
+    pub skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// An item of the source store that was renamed or left out
+pub struct MergeItem {
+    #[serde(rename = "type")]
+    pub item: ItemType,
+
+    /// The dataset of the item, for data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub set: Option<String>,
+
+    /// The identifier of the item in the source store
+    pub id: String,
+
+    /// The new identifier of the item in the target store, for renamed items
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+
+    /// Why the item was left out, for skipped items
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default)]
+/// What a merge of one store into another did
+pub struct MergeReport {
+    pub resources: MergeCount,
+    pub datasets: MergeCount,
+    pub keys: MergeCount,
+    pub data: MergeCount,
+    pub annotations: MergeCount,
+
+    /// Resources of the source store that were aligned with a resource with the same text but another identifier in the target store (source identifier to target identifier)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aligned: BTreeMap<String, String>,
+
+    /// Items that were added under a new identifier because theirs was taken
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renamed: Vec<MergeItem>,
+
+    /// Items that were left out, because their identifier was taken or because they depend on items that were left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<MergeItem>,
+}
+
+impl MergeReport {
+    fn count(&mut self, item: ItemType) -> &mut MergeCount {
+        match item {
+            ItemType::Resource => &mut self.resources,
+            ItemType::Data => &mut self.data,
+            ItemType::Annotation => &mut self.annotations,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+/// The changes a merge makes to the target store. These are computed in full before anything is changed,
+/// and logged as such, so the merge can be replayed without the source store.
+pub struct MergePlan {
+    resources: Vec<PlannedResource>,
+    datasets: Vec<String>,
+    keys: Vec<PlannedKey>,
+    data: Vec<PlannedData>,
+    /// Annotations in STAM JSON, referring to identifiers in the target store
+    annotations: Vec<Value>,
+    /// Annotations without identifier in the target store that planned annotations refer to, as `{"match": n}` instead of an identifier.
+    /// These are found again by their target and data when the plan is applied, temporary identifiers are not stable across reloads.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    matches: Vec<PlannedMatch>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PlannedResource {
+    id: String,
+    text: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PlannedKey {
+    set: String,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PlannedMatch {
+    /// The target selector in STAM JSON, which may refer to earlier matches
+    target: Value,
+    /// The data as (set, key, value in JSON)
+    data: BTreeSet<(String, String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PlannedData {
+    set: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    key: String,
+    value: DataValue,
+}
+
+impl MergePlan {
+    /// Applies the changes to the target store. If this fails halfway, the store must be rolled back by the caller.
+    pub fn apply(&self, store: &mut AnnotationStore) -> Result<(), ApiError> {
+        for resource in self.resources.iter() {
+            store.add_resource(
+                TextResourceBuilder::new()
+                    .with_id(resource.id.as_str())
+                    .with_text(resource.text.as_str()),
+            )?;
+        }
+        for id in self.datasets.iter() {
+            store.add_dataset(AnnotationDataSetBuilder::new().with_id(id.as_str()))?;
+        }
+        for key in self.keys.iter() {
+            let dataset: &mut AnnotationDataSet = store.get_mut(key.set.as_str())?;
+            StoreFor::<DataKey>::insert(dataset, DataKey::new(key.key.as_str()))?;
+        }
+        for data in self.data.iter() {
+            let mut builder = AnnotationDataBuilder::new()
+                .with_dataset(data.set.as_str().into())
+                .with_key(data.key.as_str().into())
+                .with_value(data.value.clone());
+            if let Some(id) = data.id.as_ref() {
+                builder = builder.with_id(id.as_str().into());
+            }
+            store.insert_data(builder)?;
+        }
+        let mut matched: Vec<String> = Vec::with_capacity(self.matches.len());
+        if !self.matches.is_empty() {
+            let targets = targets(store)?;
+            for planned in self.matches.iter() {
+                let mut target = planned.target.clone();
+                resolve_matches(&mut target, &matched)?;
+                let existing = targets
+                    .get(&target.to_string())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|handle| store.annotation(*handle))
+                    .find(|existing| {
+                        existing.id().is_none() && annotation_data(existing) == planned.data
+                    })
+                    .ok_or(ApiError::InternalError(
+                        "An annotation the merge refers to no longer exists",
+                    ))?;
+                matched.push(reference(&existing)?);
+            }
+        }
+        for annotation in self.annotations.iter() {
+            let mut annotation = annotation.clone();
+            if let Some(target) = annotation.get_mut("target") {
+                resolve_matches(target, &matched)?;
+            }
+            let builder: AnnotationBuilder = serde_json::from_value(annotation)
+                .map_err(|_| ApiError::InternalError("Invalid annotation in merge"))?;
+            store.annotate(builder)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces references to matched annotations (`{"match": n}`) in a target selector by their (temporary) identifiers
+fn resolve_matches(selector: &mut Value, matched: &[String]) -> Result<(), ApiError> {
+    let Some(object) = selector.as_object_mut() else {
+        return Ok(());
+    };
+    if let Some(Value::Object(reference)) = object.get("annotation") {
+        let id = reference
+            .get("match")
+            .and_then(|index| index.as_u64())
+            .and_then(|index| matched.get(index as usize))
+            .ok_or(ApiError::InternalError("Invalid annotation in merge"))?;
+        object.insert("annotation".to_string(), id.as_str().into());
+    }
+    if let Some(Value::Array(selectors)) = object.get_mut("selectors") {
+        for selector in selectors.iter_mut() {
+            resolve_matches(selector, matched)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns all annotations of a store by their target selector in STAM JSON
+fn targets(store: &AnnotationStore) -> Result<HashMap<String, Vec<AnnotationHandle>>, ApiError> {
+    let mut targets: HashMap<String, Vec<AnnotationHandle>> = HashMap::new();
+    for existing in store.annotations() {
+        let json = existing.as_ref().to_json_value(store)?;
+        targets
+            .entry(json["target"].to_string())
+            .or_default()
+            .push(existing.handle());
+    }
+    Ok(targets)
+}
+
+/// Returns the data of an annotation as (set, key, value in JSON), for comparison
+fn annotation_data(annotation: &ResultItem<Annotation>) -> BTreeSet<(String, String, String)> {
+    annotation
+        .data()
+        .map(|data| {
+            (
+                data.set().id().unwrap_or_default().to_string(),
+                data.key().as_ref().as_str().to_string(),
+                json!(data.value()).to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Everything of the source store that is needed for a merge, detached from the store so it need not stay locked
+pub struct Source {
+    resources: Vec<(String, String)>,
+    datasets: Vec<SourceDataSet>,
+    annotations: Vec<SourceAnnotation>,
+}
+
+struct SourceDataSet {
+    id: String,
+    keys: Vec<String>,
+    data: Vec<SourceData>,
+}
+
+struct SourceData {
+    /// The public identifier, or the temporary one if there is none
+    reference: String,
+    id: Option<String>,
+    key: String,
+    value: DataValue,
+}
+
+struct SourceAnnotation {
+    /// The public identifier, or the temporary one if there is none
+    reference: String,
+    id: Option<String>,
+    /// The target selector in STAM JSON
+    target: Value,
+    /// References to data as (set, reference)
+    data: Vec<(String, String)>,
+}
+
+impl Source {
+    pub fn extract(store: &AnnotationStore) -> Result<Self, ApiError> {
+        let mut resources = Vec::new();
+        for resource in store.resources() {
+            let id = resource.id().ok_or(ApiError::InvalidArgument(
+                "Resources and datasets must have an identifier to be merged",
+            ))?;
+            resources.push((id.to_string(), resource.text().to_string()));
+        }
+        let mut datasets = Vec::new();
+        for dataset in store.datasets() {
+            let id = dataset.id().ok_or(ApiError::InvalidArgument(
+                "Resources and datasets must have an identifier to be merged",
+            ))?;
+            let keys = dataset
+                .keys()
+                .map(|key| key.as_ref().as_str().to_string())
+                .collect();
+            let mut data = Vec::new();
+            for item in dataset.data() {
+                data.push(SourceData {
+                    reference: reference(&item)?,
+                    id: item.id().map(|id| id.to_string()),
+                    key: item.key().as_ref().as_str().to_string(),
+                    value: item.value().clone(),
+                });
+            }
+            datasets.push(SourceDataSet {
+                id: id.to_string(),
+                keys,
+                data,
+            });
+        }
+        let mut annotations = Vec::new();
+        for annotation in store.annotations() {
+            let mut json = annotation.as_ref().to_json_value(store)?;
+            let mut data = Vec::new();
+            for item in annotation.data() {
+                let set = item.set().id().unwrap_or_default().to_string();
+                data.push((set, reference(&item)?));
+            }
+            annotations.push(SourceAnnotation {
+                reference: reference(&annotation)?,
+                id: annotation.id().map(|id| id.to_string()),
+                target: json["target"].take(),
+                data,
+            });
+        }
+        Ok(Self {
+            resources,
+            datasets,
+            annotations,
+        })
+    }
+}
+
+fn reference<T>(item: &ResultItem<T>) -> Result<String, ApiError>
+where
+    T: Storable,
+{
+    match item.id() {
+        Some(id) => Ok(id.to_string()),
+        None => Ok(item.as_ref().temp_id()?),
+    }
+}
+
+/// Computes how the source store is merged into the target store, without changing anything yet.
+/// Resources are aligned by identifier, or else by their text. Datasets and keys are merged by identifier,
+/// data by identifier or else by key and value. Annotations that are identical to those in the target store are not added again.
+pub fn plan(
+    source: &Source,
+    store: &AnnotationStore,
+    collisions: Collisions,
+) -> Result<(MergePlan, MergeReport), ApiError> {
+    let mut merger = Merger {
+        store,
+        collisions,
+        plan: MergePlan::default(),
+        report: MergeReport::default(),
+        sourcedata: HashMap::new(),
+        resources: HashMap::new(),
+        data: HashMap::new(),
+        annotations: HashMap::new(),
+        targets: None,
+    };
+    for dataset in source.datasets.iter() {
+        for data in dataset.data.iter() {
+            merger
+                .sourcedata
+                .insert((dataset.id.as_str(), data.reference.as_str()), data);
+        }
+    }
+    merger.merge_resources(&source.resources)?;
+    merger.merge_datasets(&source.datasets)?;
+    merger.merge_annotations(&source.annotations)?;
+    Ok((merger.plan, merger.report))
+}
+
+struct Merger<'a> {
+    store: &'a AnnotationStore,
+    collisions: Collisions,
+    plan: MergePlan,
+    report: MergeReport,
+    /// Data of the source store by (set, reference)
+    sourcedata: HashMap<(&'a str, &'a str), &'a SourceData>,
+    /// Identifiers in the target store of the resources of the source store, `None` if left out
+    resources: HashMap<String, Option<String>>,
+    /// References to the data of the source store, by (set, reference), as data references for annotations in the target store, `None` if left out
+    data: HashMap<(String, String), Option<Value>>,
+    /// References in the target store to the annotations of the source store, by reference, `None` if left out,
+    /// or without identifier and not referred to by other annotations
+    annotations: HashMap<String, Option<AnnotationRef>>,
+    /// Annotations of the target store by their target selector in STAM JSON, only built when needed
+    targets: Option<HashMap<String, Vec<AnnotationHandle>>>,
+}
+
+/// A reference to an annotation in the target store
+enum AnnotationRef {
+    Id(String),
+    /// An existing annotation without identifier, by its temporary identifier and its index in [`MergePlan::matches`]
+    Matched {
+        temp_id: String,
+        index: usize,
+    },
+}
+
+impl<'a> Merger<'a> {
+    fn merge_resources(&mut self, resources: &[(String, String)]) -> Result<(), ApiError> {
+        let mut texts: HashMap<&str, &str> = HashMap::new();
+        for resource in self.store.resources() {
+            if let Some(id) = resource.id() {
+                texts.entry(resource.text()).or_insert(id);
+            }
+        }
+        let sourceids: HashSet<&str> = resources.iter().map(|(id, _)| id.as_str()).collect();
+        let mut newids: HashSet<String> = HashSet::new();
+        for (id, text) in resources {
+            let target = if let Some(existing) = self.store.resource(id.as_str()) {
+                if existing.text() == text {
+                    self.report.resources.matched += 1;
+                    Some(id.clone())
+                } else {
+                    let to = free_id(id, |candidate| {
+                        self.store.resource(candidate).is_some()
+                            || sourceids.contains(candidate)
+                            || newids.contains(candidate)
+                    });
+                    self.collide(
+                        ItemType::Resource,
+                        None,
+                        id,
+                        to,
+                        "A resource with the same identifier but a different text exists in the target store",
+                    )?
+                    .inspect(|to| self.plan_resource(to, text, &mut newids))
+                }
+            } else if let Some(existing) = texts.get(text.as_str()) {
+                self.report.resources.matched += 1;
+                self.report.aligned.insert(id.clone(), existing.to_string());
+                Some(existing.to_string())
+            } else {
+                self.report.resources.added += 1;
+                self.plan_resource(id, text, &mut newids);
+                Some(id.clone())
+            };
+            self.resources.insert(id.clone(), target);
+        }
+        Ok(())
+    }
+
+    fn plan_resource(&mut self, id: &str, text: &str, newids: &mut HashSet<String>) {
+        newids.insert(id.to_string());
+        self.plan.resources.push(PlannedResource {
+            id: id.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    fn merge_datasets(&mut self, datasets: &[SourceDataSet]) -> Result<(), ApiError> {
+        for dataset in datasets {
+            let set = dataset.id.as_str();
+            let existing = self.store.dataset(set);
+            if existing.is_some() {
+                self.report.datasets.matched += 1;
+            } else {
+                self.report.datasets.added += 1;
+                self.plan.datasets.push(set.to_string());
+            }
+            for key in dataset.keys.iter() {
+                if existing
+                    .as_ref()
+                    .and_then(|existing| existing.key(key.as_str()))
+                    .is_some()
+                {
+                    self.report.keys.matched += 1;
+                } else {
+                    self.report.keys.added += 1;
+                    self.plan.keys.push(PlannedKey {
+                        set: set.to_string(),
+                        key: key.clone(),
+                    });
+                }
+            }
+            let sourceids: HashSet<&str> = dataset
+                .data
+                .iter()
+                .filter_map(|data| data.id.as_deref())
+                .collect();
+            let mut newids: HashSet<String> = HashSet::new();
+            for data in dataset.data.iter() {
+                let target = if let Some(id) = data.id.as_ref() {
+                    let found = existing
+                        .as_ref()
+                        .and_then(|existing| existing.annotationdata(id.as_str()));
+                    let id = match found {
+                        Some(found)
+                            if found.key().as_ref().as_str() == data.key
+                                && *found.value() == data.value =>
+                        {
+                            self.report.data.matched += 1;
+                            Some(id.clone())
+                        }
+                        Some(_) => {
+                            let to = free_id(id, |candidate| {
+                                existing
+                                    .as_ref()
+                                    .and_then(|existing| existing.annotationdata(candidate))
+                                    .is_some()
+                                    || sourceids.contains(candidate)
+                                    || newids.contains(candidate)
+                            });
+                            self.collide(
+                                ItemType::Data,
+                                Some(set),
+                                id,
+                                to,
+                                "Data with the same identifier but a different key or value exists in the target store",
+                            )?
+                            .inspect(|to| self.plan_data(set, Some(to), data, &mut newids))
+                        }
+                        None => {
+                            self.report.data.added += 1;
+                            self.plan_data(set, Some(id), data, &mut newids);
+                            Some(id.clone())
+                        }
+                    };
+                    id.map(|id| json!({ "@type": "AnnotationData", "@id": id, "set": set }))
+                } else {
+                    //data without an identifier is matched by key and value
+                    if existing
+                        .as_ref()
+                        .and_then(|existing| {
+                            existing
+                                .as_ref()
+                                .data_by_value(data.key.as_str(), &data.value)
+                        })
+                        .is_some()
+                    {
+                        self.report.data.matched += 1;
+                    } else {
+                        self.report.data.added += 1;
+                        self.plan_data(set, None, data, &mut newids);
+                    }
+                    Some(json!({
+                        "@type": "AnnotationData",
+                        "set": set,
+                        "key": data.key,
+                        "value": data.value,
+                    }))
+                };
+                self.data
+                    .insert((set.to_string(), data.reference.clone()), target);
+            }
+        }
+        Ok(())
+    }
+
+    fn plan_data(
+        &mut self,
+        set: &str,
+        id: Option<&str>,
+        data: &SourceData,
+        newids: &mut HashSet<String>,
+    ) {
+        if let Some(id) = id {
+            newids.insert(id.to_string());
+        }
+        self.plan.data.push(PlannedData {
+            set: set.to_string(),
+            id: id.map(|id| id.to_string()),
+            key: data.key.clone(),
+            value: data.value.clone(),
+        });
+    }
+
+    fn merge_annotations(&mut self, annotations: &[SourceAnnotation]) -> Result<(), ApiError> {
+        //annotations without an identifier get one if other annotations refer to them
+        let mut referenced: HashSet<String> = HashSet::new();
+        for annotation in annotations {
+            references(&annotation.target, &mut referenced);
+        }
+        let sourceids: HashSet<&str> = annotations
+            .iter()
+            .filter_map(|annotation| annotation.id.as_deref())
+            .collect();
+        let mut newids: HashSet<String> = HashSet::new();
+        for annotation in annotations {
+            //the target as it is now for comparison, and as it is in the plan
+            let mut target = annotation.target.clone();
+            let mut plannedtarget = annotation.target.clone();
+            let data = match self
+                .remap_selector(&mut target, false)
+                .and_then(|_| self.remap_selector(&mut plannedtarget, true))
+                .and_then(|_| self.remap_data(annotation))
+            {
+                Ok(data) => data,
+                Err(reason) => {
+                    self.skip(ItemType::Annotation, None, &annotation.reference, reason);
+                    self.annotations.insert(annotation.reference.clone(), None);
+                    continue;
+                }
+            };
+            let id = if let Some(id) = annotation.id.as_ref() {
+                match self.store.annotation(id.as_str()) {
+                    Some(existing) if self.is_identical(&existing, &target, annotation)? => {
+                        self.report.annotations.matched += 1;
+                        self.annotations
+                            .insert(id.clone(), Some(AnnotationRef::Id(id.clone())));
+                        continue;
+                    }
+                    Some(_) => {
+                        let to = free_id(id, |candidate| {
+                            self.store.annotation(candidate).is_some()
+                                || sourceids.contains(candidate)
+                                || newids.contains(candidate)
+                        });
+                        let to = self.collide(
+                            ItemType::Annotation,
+                            None,
+                            id,
+                            to,
+                            "An annotation with the same identifier but a different target or data exists in the target store",
+                        )?;
+                        if to.is_none() {
+                            self.annotations.insert(id.clone(), None);
+                            continue;
+                        }
+                        to
+                    }
+                    None => {
+                        self.report.annotations.added += 1;
+                        Some(id.clone())
+                    }
+                }
+            } else if let Some(existing) = self.find_identical(&target, annotation)? {
+                //annotations without an identifier are matched by their target and data instead
+                self.report.annotations.matched += 1;
+                let to = match existing.id() {
+                    Some(id) => Some(AnnotationRef::Id(id.to_string())),
+                    None if referenced.contains(&annotation.reference) => {
+                        self.plan.matches.push(PlannedMatch {
+                            target: plannedtarget,
+                            data: annotation_data(&existing),
+                        });
+                        Some(AnnotationRef::Matched {
+                            temp_id: reference(&existing)?,
+                            index: self.plan.matches.len() - 1,
+                        })
+                    }
+                    None => None,
+                };
+                self.annotations.insert(annotation.reference.clone(), to);
+                continue;
+            } else {
+                self.report.annotations.added += 1;
+                if referenced.contains(&annotation.reference) {
+                    Some(stam::generate_id("", ""))
+                } else {
+                    None
+                }
+            };
+            let mut json = Map::new();
+            json.insert("@type".to_string(), "Annotation".into());
+            if let Some(id) = id.as_ref() {
+                newids.insert(id.clone());
+                json.insert("@id".to_string(), id.as_str().into());
+            }
+            json.insert("target".to_string(), plannedtarget);
+            json.insert("data".to_string(), Value::Array(data));
+            self.plan.annotations.push(Value::Object(json));
+            self.annotations
+                .insert(annotation.reference.clone(), id.map(AnnotationRef::Id));
+        }
+        Ok(())
+    }
+
+    /// Rewrites the references in a target selector to those in the target store, returns why the annotation has to be left out otherwise.
+    /// For the plan (`planned`), references to matched annotations without identifier are rewritten to `{"match": n}`.
+    fn remap_selector(&self, selector: &mut Value, planned: bool) -> Result<(), String> {
+        let Some(object) = selector.as_object_mut() else {
+            return Ok(());
+        };
+        if let Some(Value::String(id)) = object.get_mut("resource") {
+            match self.resources.get(id.as_str()) {
+                Some(Some(to)) => *id = to.clone(),
+                _ => return Err(format!("depends on skipped resource {}", id)),
+            }
+        }
+        if let Some(Value::String(id)) = object.get("annotation") {
+            let to = match self.annotations.get(id.as_str()) {
+                Some(Some(AnnotationRef::Id(to))) => to.as_str().into(),
+                Some(Some(AnnotationRef::Matched { index, .. })) if planned => {
+                    json!({ "match": index })
+                }
+                Some(Some(AnnotationRef::Matched { temp_id, .. })) => temp_id.as_str().into(),
+                _ => return Err(format!("depends on skipped annotation {}", id)),
+            };
+            object.insert("annotation".to_string(), to);
+        }
+        if let (Some(Value::String(set)), Some(Value::String(id))) =
+            (object.get("annotationset"), object.get("data"))
+        {
+            let to = self
+                .data
+                .get(&(set.clone(), id.clone()))
+                .and_then(|data| data.as_ref())
+                .and_then(|data| data.get("@id"))
+                .cloned()
+                .ok_or_else(|| {
+                    format!(
+                        "depends on data {} in set {} that was skipped or has no identifier",
+                        id, set
+                    )
+                })?;
+            object.insert("data".to_string(), to);
+            //stam serializes these with the type of a key selector
+            object.insert("@type".to_string(), "AnnotationDataSelector".into());
+        }
+        if let Some(Value::Array(selectors)) = object.get_mut("selectors") {
+            for selector in selectors.iter_mut() {
+                self.remap_selector(selector, planned)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the data references of an annotation for the target store, or why the annotation has to be left out
+    fn remap_data(&self, annotation: &SourceAnnotation) -> Result<Vec<Value>, String> {
+        let mut data = Vec::with_capacity(annotation.data.len());
+        for (set, reference) in annotation.data.iter() {
+            match self.data.get(&(set.clone(), reference.clone())) {
+                Some(Some(item)) => data.push(item.clone()),
+                _ => {
+                    return Err(format!(
+                        "depends on skipped data {} in set {}",
+                        reference, set
+                    ))
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Finds an annotation in the target store with the same target and data as an annotation of the source store
+    fn find_identical(
+        &mut self,
+        target: &Value,
+        annotation: &SourceAnnotation,
+    ) -> Result<Option<ResultItem<'a, Annotation>>, ApiError> {
+        if self.targets.is_none() {
+            self.targets = Some(targets(self.store)?);
+        }
+        let handles = self
+            .targets
+            .as_ref()
+            .and_then(|targets| targets.get(&target.to_string()));
+        for handle in handles.into_iter().flatten() {
+            if let Some(existing) = self.store.annotation(*handle) {
+                if self.is_identical(&existing, target, annotation)? {
+                    return Ok(Some(existing));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tests whether an annotation in the target store has the same target and data as an annotation of the source store
+    fn is_identical(
+        &self,
+        existing: &ResultItem<Annotation>,
+        target: &Value,
+        annotation: &SourceAnnotation,
+    ) -> Result<bool, ApiError> {
+        let json = existing.as_ref().to_json_value(self.store)?;
+        if json.get("target") != Some(target) {
+            return Ok(false);
+        }
+        let existingdata = annotation_data(existing);
+        let sourcedata: Option<BTreeSet<(String, String, String)>> = annotation
+            .data
+            .iter()
+            .map(|(set, reference)| {
+                self.sourcedata
+                    .get(&(set.as_str(), reference.as_str()))
+                    .map(|data| (set.clone(), data.key.clone(), json!(data.value).to_string()))
+            })
+            .collect();
+        //data that can not be resolved can not be compared either
+        Ok(sourcedata.is_some_and(|sourcedata| existingdata == sourcedata))
+    }
+
+    /// Handles an identifier that is taken by a different item in the target store,
+    /// returns the identifier to add the item under, or `None` if it is left out
+    fn collide(
+        &mut self,
+        item: ItemType,
+        set: Option<&str>,
+        id: &str,
+        to: String,
+        error: &'static str,
+    ) -> Result<Option<String>, ApiError> {
+        match self.collisions {
+            Collisions::Fail => Err(ApiError::Conflict(error)),
+            Collisions::Skip => {
+                self.skip(item, set, id, "identifier is taken".to_string());
+                Ok(None)
+            }
+            Collisions::Rename => {
+                self.report.count(item).renamed += 1;
+                self.report.renamed.push(MergeItem {
+                    item,
+                    set: set.map(|set| set.to_string()),
+                    id: id.to_string(),
+                    to: Some(to.clone()),
+                    reason: None,
+                });
+                Ok(Some(to))
+            }
+        }
+    }
+
+    fn skip(&mut self, item: ItemType, set: Option<&str>, id: &str, reason: String) {
+        self.report.count(item).skipped += 1;
+        self.report.skipped.push(MergeItem {
+            item,
+            set: set.map(|set| set.to_string()),
+            id: id.to_string(),
+            to: None,
+            reason: Some(reason),
+        });
+    }
+}
+
+/// Returns the first identifier of the form `{id}.{n}` that is not taken
+fn free_id(id: &str, taken: impl Fn(&str) -> bool) -> String {
+    (1..)
+        .map(|n| format!("{}.{}", id, n))
+        .find(|candidate| !taken(candidate))
+        .expect("there is always a free identifier")
+}
+
+/// Collects the temporary identifiers of annotations that a target selector refers to
+fn references(selector: &Value, referenced: &mut HashSet<String>) {
+    if let Some(Value::String(id)) = selector.get("annotation") {
+        if id.starts_with('!') {
+            referenced.insert(id.clone());
+        }
+    }
+    if let Some(Value::Array(selectors)) = selector.get("selectors") {
+        for selector in selectors {
+            references(selector, referenced);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stam::Config;
+
+    fn store(annotations: &str) -> AnnotationStore {
+        AnnotationStore::from_str(
+            &format!(
+                r#"{{"@type":"AnnotationStore","@id":"t",
+                "resources":[{{"@type":"TextResource","@id":"doc","text":"Hello world"}}],
+                "annotationsets":[{{"@type":"AnnotationDataSet","@id":"s","keys":[{{"@type":"DataKey","@id":"k"}}]}}],
+                "annotations":[{}]}}"#,
+                annotations
+            ),
+            Config::default(),
+        )
+        .unwrap()
+    }
+
+    /// An annotation in STAM JSON, with an optional identifier, on a text selection or (if `end` is `None`) on another annotation
+    fn annotation(id: Option<&str>, target: &str, begin: usize, end: Option<usize>) -> String {
+        let target = match end {
+            Some(end) => format!(
+                r#"{{"@type":"TextSelector","resource":"{}","offset":{{"@type":"Offset","begin":{{"@type":"BeginAlignedCursor","value":{}}},"end":{{"@type":"BeginAlignedCursor","value":{}}}}}}}"#,
+                target, begin, end
+            ),
+            None => format!(
+                r#"{{"@type":"AnnotationSelector","annotation":"{}"}}"#,
+                target
+            ),
+        };
+        format!(
+            r#"{{"@type":"Annotation",{}"target":{},"data":[{{"@type":"AnnotationData","set":"s","key":"k","value":{{"@type":"String","value":"v"}}}}]}}"#,
+            id.map(|id| format!(r#""@id":"{}","#, id))
+                .unwrap_or_default(),
+            target
+        )
+    }
+
+    fn merge(
+        source: &AnnotationStore,
+        target: &mut AnnotationStore,
+        collisions: Collisions,
+    ) -> Result<MergeReport, ApiError> {
+        let (plan, report) = plan(&Source::extract(source)?, target, collisions)?;
+        plan.apply(target)?;
+        Ok(report)
+    }
+
+    #[test]
+    fn collisions() {
+        //the source annotates another part of the text under the same identifier, and has an annotation on that one
+        let source = store(&format!(
+            "{},{}",
+            annotation(Some("a1"), "doc", 6, Some(11)),
+            annotation(Some("a2"), "a1", 0, None)
+        ));
+        for (collisions, renamed, skipped, annotations) in [
+            (Collisions::Fail, 0, 0, None),
+            (Collisions::Skip, 0, 2, Some(1)),
+            (Collisions::Rename, 1, 0, Some(3)),
+        ] {
+            let mut target = store(&annotation(Some("a1"), "doc", 0, Some(5)));
+            match merge(&source, &mut target, collisions) {
+                Ok(report) => {
+                    assert_eq!(report.annotations.renamed, renamed, "{:?}", collisions);
+                    assert_eq!(report.annotations.skipped, skipped, "{:?}", collisions);
+                    assert_eq!(
+                        Some(target.annotations_len()),
+                        annotations,
+                        "{:?}",
+                        collisions
+                    );
+                }
+                Err(err) => {
+                    assert!(matches!(err, ApiError::Conflict(_)), "{:?}", collisions);
+                    assert_eq!(annotations, None, "{:?}", collisions);
+                    assert_eq!(target.annotations_len(), 1);
+                }
+            }
+            if collisions == Collisions::Rename {
+                //the annotation on the renamed one follows it
+                let a2 = target.annotation("a2").unwrap();
+                assert_eq!(
+                    a2.annotations_in_targets(stam::AnnotationDepth::One)
+                        .next()
+                        .and_then(|a1| a1.id().map(|id| id.to_string())),
+                    Some("a1.1".to_string())
+                );
+                assert_eq!(
+                    target.annotation("a1.1").unwrap().text_simple(),
+                    Some("world")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn align_by_text() {
+        let mut source = AnnotationStore::new(Config::default());
+        source
+            .add_resource(
+                TextResourceBuilder::new()
+                    .with_id("other")
+                    .with_text("Hello world"),
+            )
+            .unwrap();
+        source
+            .annotate(
+                AnnotationBuilder::new()
+                    .with_id("a1")
+                    .with_target(stam::SelectorBuilder::textselector(
+                        "other",
+                        stam::Offset::simple(0, 5),
+                    ))
+                    .with_data("s", "k", "v"),
+            )
+            .unwrap();
+        let mut target = store("");
+        let report = merge(&source, &mut target, Collisions::Fail).unwrap();
+        assert_eq!(
+            report.aligned.get("other").map(|id| id.as_str()),
+            Some("doc")
+        );
+        assert_eq!(report.resources.matched, 1);
+        assert_eq!(target.resources_len(), 1);
+        assert_eq!(
+            target.annotation("a1").unwrap().text_simple(),
+            Some("Hello")
+        );
+    }
+
+    #[test]
+    fn chained_targets() {
+        //annotations without identifier, referred to by temporary identifier, on which other annotations depend
+        let annotations = format!(
+            "{},{},{}",
+            annotation(None, "doc", 0, Some(5)),
+            annotation(None, "!A0", 0, None),
+            annotation(Some("a3"), "!A1", 0, None)
+        );
+        let source = store(&annotations);
+
+        //merged into an identical store, everything matches
+        let mut target = store(&annotations);
+        let report = merge(&source, &mut target, Collisions::Fail).unwrap();
+        assert_eq!(report.annotations.matched, 3);
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        assert_eq!(target.annotations_len(), 3);
+
+        //merged into a store with only the first one, the others are added on top of it
+        let mut target = store(&annotation(None, "doc", 0, Some(5)));
+        let report = merge(&source, &mut target, Collisions::Fail).unwrap();
+        assert_eq!(report.annotations.matched, 1);
+        assert_eq!(report.annotations.added, 2);
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        let a1 = target
+            .annotation("a3")
+            .unwrap()
+            .annotations_in_targets(stam::AnnotationDepth::One)
+            .next()
+            .unwrap()
+            .annotations_in_targets(stam::AnnotationDepth::One)
+            .next()
+            .unwrap();
+        assert_eq!(a1.handle(), target.annotation("!A0").unwrap().handle());
+    }
+
+    #[test]
+    fn stable_references() {
+        //an annotation on an existing annotation without identifier
+        let source = store(&format!(
+            "{},{}",
+            annotation(None, "doc", 0, Some(5)),
+            annotation(Some("a2"), "!A0", 0, None)
+        ));
+        let target = store(&format!(
+            "{},{}",
+            annotation(Some("a0"), "doc", 6, Some(11)),
+            annotation(None, "doc", 0, Some(5))
+        ));
+        let (plan, _) = plan(
+            &Source::extract(&source).unwrap(),
+            &target,
+            Collisions::Fail,
+        )
+        .unwrap();
+        //the plan is replayed from the write-ahead log on the store as it was saved and reloaded after a0 was removed,
+        //so the annotation it refers to has another temporary identifier
+        let plan: MergePlan = serde_json::from_value(serde_json::to_value(&plan).unwrap()).unwrap();
+        let mut target = store(&annotation(None, "doc", 0, Some(5)));
+        plan.apply(&mut target).unwrap();
+        let a1 = target
+            .annotation("a2")
+            .unwrap()
+            .annotations_in_targets(stam::AnnotationDepth::One)
+            .next()
+            .unwrap();
+        assert_eq!(a1.handle(), target.annotation("!A0").unwrap().handle());
+    }
+}
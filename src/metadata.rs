@@ -0,0 +1,157 @@
+use crate::common::ApiError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+/// Descriptive metadata of a store, kept alongside the store. All fields are optional.
+pub struct StoreMetadata {
+    /// A human readable title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    /// A human readable description of the contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// The license the store is available under, preferably a URL (or an SPDX identifier)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+
+    /// A statement on the origin of the store and how it was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<String>,
+
+    /// Who to contact about the store, an e-mail address or URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+
+    /// Languages of the texts, as BCP 47 language tags (e.g. `en`, `nl-BE`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub languages: Vec<String>,
+}
+
+impl StoreMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.description.is_none()
+            && self.license.is_none()
+            && self.provenance.is_none()
+            && self.contact.is_none()
+            && self.languages.is_empty()
+    }
+
+    /// Checks that the language tags are well-formed
+    pub fn validate(&self) -> Result<(), ApiError> {
+        for language in self.languages.iter() {
+            if language.is_empty()
+                || language.split('-').any(|subtag| {
+                    subtag.is_empty()
+                        || subtag.len() > 8
+                        || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+                })
+            {
+                return Err(ApiError::InvalidArgument(
+                    "Languages must be BCP 47 language tags (e.g. en, nl-BE)",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the store as a DCAT dataset in JSON-LD, for use by data catalogues.
+    /// Without `context`, the description is meant for embedding in a catalogue that provides it.
+    pub fn to_dcat(&self, id: &str, url: &str, context: bool) -> Value {
+        let mut dataset = Map::new();
+        if context {
+            dataset.insert("@context".to_string(), dcat_context());
+        }
+        dataset.insert("@id".to_string(), url.into());
+        dataset.insert("@type".to_string(), "dcat:Dataset".into());
+        dataset.insert("dct:identifier".to_string(), id.into());
+        if let Some(title) = self.title.as_ref() {
+            dataset.insert("dct:title".to_string(), title.as_str().into());
+        }
+        if let Some(description) = self.description.as_ref() {
+            dataset.insert("dct:description".to_string(), description.as_str().into());
+        }
+        if let Some(license) = self.license.as_ref() {
+            let license = if is_url(license) {
+                json!({ "@id": license })
+            } else {
+                json!({ "@type": "dct:LicenseDocument", "rdfs:label": license })
+            };
+            dataset.insert("dct:license".to_string(), license);
+        }
+        if let Some(provenance) = self.provenance.as_ref() {
+            dataset.insert(
+                "dct:provenance".to_string(),
+                json!({ "@type": "dct:ProvenanceStatement", "rdfs:label": provenance }),
+            );
+        }
+        if let Some(contact) = self.contact.as_ref() {
+            let contact = if is_url(contact) {
+                json!({ "@type": "vcard:Kind", "vcard:hasURL": { "@id": contact } })
+            } else if contact.contains('@') {
+                json!({ "@type": "vcard:Kind", "vcard:hasEmail": { "@id": format!("mailto:{}", contact) } })
+            } else {
+                json!({ "@type": "vcard:Kind", "vcard:fn": contact })
+            };
+            dataset.insert("dcat:contactPoint".to_string(), contact);
+        }
+        if !self.languages.is_empty() {
+            dataset.insert("dct:language".to_string(), self.languages.clone().into());
+        }
+        dataset.insert(
+            "dcat:distribution".to_string(),
+            json!({
+                "@type": "dcat:Distribution",
+                "dcat:accessURL": { "@id": url },
+                "dcat:mediaType": "application/json",
+            }),
+        );
+        Value::Object(dataset)
+    }
+}
+
+/// The JSON-LD context for DCAT descriptions
+pub fn dcat_context() -> Value {
+    json!({
+        "dcat": "http://www.w3.org/ns/dcat#",
+        "dct": "http://purl.org/dc/terms/",
+        "rdfs": "http://www.w3.org/2000/01/rdf-schema#",
+        "vcard": "http://www.w3.org/2006/vcard/ns#",
+    })
+}
+
+fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// Reads the metadata of a store from file, returns `None` if the store has no metadata
+pub fn read(filename: &Path) -> Result<Option<StoreMetadata>, ApiError> {
+    if !filename.exists() {
+        return Ok(None);
+    }
+    let data = std::fs::read_to_string(filename)
+        .map_err(|_| ApiError::InternalError("Unable to read metadata"))?;
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(|_| ApiError::InternalError("Invalid metadata file"))
+}
+
+/// Writes the metadata of a store to file, the file is removed if the metadata is empty
+pub fn write(filename: &Path, metadata: &StoreMetadata) -> Result<(), ApiError> {
+    if metadata.is_empty() {
+        if filename.exists() {
+            std::fs::remove_file(filename)
+                .map_err(|_| ApiError::InternalError("Unable to remove metadata"))?;
+        }
+        return Ok(());
+    }
+    let data = serde_json::to_string_pretty(metadata)
+        .map_err(|_| ApiError::InternalError("Unable to serialize metadata"))?;
+    std::fs::write(filename, data).map_err(|_| ApiError::InternalError("Unable to write metadata"))
+}
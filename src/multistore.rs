@@ -1,13 +1,28 @@
+use crate::backup::{self, Backup, Backups};
+use crate::cache::QueryCache;
 use crate::common::ApiError;
-use stam::{AnnotationStore, AssociatedFile, Config, TextResourceBuilder, WebAnnoConfig};
-use std::collections::HashMap;
+use crate::history::{self, Change, History, Revision};
+use crate::merge::{self, Collisions, MergeReport};
+use crate::metadata::{self, StoreMetadata};
+use crate::savedqueries::{self, SavedQuery};
+use crate::wal::{self, Journal};
+use serde::Serialize;
+use stam::{
+    AnnotationStore, AnnotationSubStore, AssociatedFile, Config, Configurable, TextResourceBuilder,
+    WebAnnoConfig,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Component, Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tracing::info;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
 
 const WAIT_INTERVAL: Duration = Duration::from_millis(100);
 
+const CONFLICT: &str =
+    "The store was changed on disk while it had unsaved changes, it must be reloaded first";
+
 #[derive(Clone)]
 pub struct StoreState {
     last_access: Duration,
@@ -16,6 +31,51 @@ pub struct StoreState {
 
     /// FlagFlag set when data is still being written from disk
     saving: bool,
+
+    /// Flag set when the store has changes that have not been saved yet
+    changed: bool,
+
+    /// Modification time of the store file when it was loaded or last saved (nanoseconds since the unix epoch)
+    modified: Option<u64>,
+
+    /// Flag set when the store file was changed on disk while the store also had unsaved changes
+    conflict: bool,
+}
+
+#[derive(Serialize, ToSchema, Clone, Debug)]
+/// An available annotation store
+pub struct StoreInfo {
+    /// Identifier of the store, for stores in subdirectories this is their path relative to the base directory
+    pub id: String,
+
+    /// Size of the store file in bytes
+    pub size: u64,
+
+    /// Modification time of the store file, in seconds since the unix epoch
+    pub modified: u64,
+
+    /// Whether the store is currently loaded
+    pub loaded: bool,
+
+    /// Whether the store has unsaved changes (loaded stores only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+
+    /// Number of annotations (loaded stores only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<usize>,
+
+    /// Number of resources (loaded stores only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<usize>,
+
+    /// Number of datasets (loaded stores only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datasets: Option<usize>,
+
+    /// Descriptive metadata of the store, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<StoreMetadata>,
 }
 
 pub struct StorePool {
@@ -25,6 +85,8 @@ pub struct StorePool {
     readonly: bool,
     no_extra_target: bool, //for webannotations
     unload_time: u64,
+    /// Maximum time a query may take
+    query_timeout: Option<Duration>,
     stores: RwLock<HashMap<String, Arc<RwLock<AnnotationStore>>>>, //the extra Arc allows us to drop the lock earlier
     states: RwLock<HashMap<String, StoreState>>,
     webannoconfigs: RwLock<HashMap<String, WebAnnoConfig>>,
@@ -32,16 +94,34 @@ pub struct StorePool {
     /// Root WebAnnoConfig that store-specific ones will be derived from
     webannoconfig: WebAnnoConfig,
 
+    /// Serializes changes to saved queries
+    savedqueries_lock: Mutex<()>,
+
+    /// Cached results of read-only queries
+    cache: QueryCache,
+
+    /// Record the history of all changes
+    history: bool,
+
+    /// Number of backups of earlier versions to keep per store
+    backups: usize,
+
     config: Config,
 }
 
 impl StorePool {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         basedir: impl Into<PathBuf>,
         baseurl: impl Into<String>,
         extension: impl Into<String>,
         readonly: bool,
         unload_time: u64,
+        query_timeout: Option<Duration>,
+        cache_size: usize,
+        cache_memory: usize,
+        history: bool,
+        backups: usize,
         no_extra_target: bool,
         webannoconfig: WebAnnoConfig,
         config: Config,
@@ -58,7 +138,12 @@ impl StorePool {
                 states: HashMap::new().into(),
                 webannoconfigs: HashMap::new().into(),
                 webannoconfig,
+                savedqueries_lock: Mutex::new(()),
+                cache: QueryCache::new(cache_size, cache_memory),
+                history,
+                backups,
                 unload_time,
+                query_timeout,
                 no_extra_target,
                 readonly,
                 config,
@@ -66,10 +151,6 @@ impl StorePool {
         }
     }
 
-    pub fn basedir(&self) -> &Path {
-        self.basedir.as_path()
-    }
-
     pub fn baseurl(&self) -> &str {
         self.baseurl.as_str()
     }
@@ -78,6 +159,14 @@ impl StorePool {
         self.extension.as_str()
     }
 
+    pub fn query_timeout(&self) -> Option<Duration> {
+        self.query_timeout
+    }
+
+    pub fn cache(&self) -> &QueryCache {
+        &self.cache
+    }
+
     pub fn webannoconfigs(&self) -> &RwLock<HashMap<String, WebAnnoConfig>> {
         &self.webannoconfigs
     }
@@ -103,7 +192,8 @@ impl StorePool {
         }
     }
 
-    pub fn map_mut<F, T>(&self, id: &str, f: F) -> Result<T, ApiError>
+    /// Changes a store. This is not public, all changes should go via [`Self::map_mut_recorded()`] so they are logged.
+    fn map_mut<F, T>(&self, id: &str, f: F) -> Result<T, ApiError>
     where
         F: FnOnce(&mut AnnotationStore) -> Result<T, ApiError>,
     {
@@ -112,12 +202,20 @@ impl StorePool {
                 "Service is configured as read-only",
             ))
         } else {
-            let _state = self.load(id)?;
+            let state = self.load(id)?;
+            if state.conflict {
+                return Err(ApiError::Conflict(CONFLICT));
+            }
             if let Ok(stores) = self.stores.write() {
                 if let Some(store) = stores.get(id).cloned() {
                     drop(stores); //compiler should be able to infer this but better safe than sorry
                     if let Ok(mut store) = store.write() {
-                        f(&mut store)
+                        let result = f(&mut store);
+                        //the store may have changed, invalidate cached results while we still hold the lock
+                        self.cache.invalidate(id);
+                        //STAM does not flag all changes itself (e.g. added annotations, or a store that was rolled back), so we keep track of this ourselves
+                        self.mark_changed(id)?;
+                        result
                     } else {
                         Err(ApiError::InternalError("Store lock got poisoned")) //only happens if a thread holding a write lock panics
                     }
@@ -130,17 +228,107 @@ impl StorePool {
         }
     }
 
+    /// Changes a store. `f` returns its result along with a description of the change it made.
+    /// The change is written to the write-ahead log before returning, and recorded in the history of the store (if enabled).
+    /// Failed changes are not logged, changes that can not be logged are undone.
+    pub fn map_mut_recorded<F, T>(&self, id: &str, user: Option<&str>, f: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut AnnotationStore) -> Result<(T, Change), ApiError>,
+    {
+        self.apply_recorded(id, user, false, f)
+            .map(|(result, _)| result)
+    }
+
+    fn mark_changed(&self, id: &str) -> Result<(), ApiError> {
+        if let Ok(mut states) = self.states.write() {
+            if let Some(state) = states.get_mut(id) {
+                state.changed = true;
+            }
+            Ok(())
+        } else {
+            Err(ApiError::InternalError("Lock poisoned"))
+        }
+    }
+
+    /// Like [`Self::map_mut_recorded()`], but applies the changes atomically: if `f` fails, all changes it made to the store are rolled back.
+    pub fn transaction<F, T>(&self, id: &str, user: Option<&str>, f: F) -> Result<T, ApiError>
+    where
+        F: FnOnce(&mut AnnotationStore) -> Result<(T, Change), ApiError>,
+    {
+        self.apply_recorded(id, user, true, f)
+            .map(|(result, _)| result)
+    }
+
+    /// Applies and logs a change, see [`Self::map_mut_recorded()`], returns the revision it was recorded as (if history is enabled).
+    /// This takes an in-memory snapshot of the entire store first, to undo the change if it can not be logged,
+    /// or if it fails and `atomic` is set.
+    fn apply_recorded<F, T>(
+        &self,
+        id: &str,
+        user: Option<&str>,
+        atomic: bool,
+        f: F,
+    ) -> Result<(T, Option<Revision>), ApiError>
+    where
+        F: FnOnce(&mut AnnotationStore) -> Result<(T, Change), ApiError>,
+    {
+        let history = if self.history {
+            Some(self.history(id)?)
+        } else {
+            None
+        };
+        let journal = self.journal(id)?;
+        self.map_mut(id, |store| {
+            if let Some(history) = history.as_ref() {
+                history.start(store)?;
+            }
+            let snapshot = history::snapshot(store)?;
+            let (result, change) = match f(store) {
+                Ok(result) => result,
+                Err(err) => {
+                    if atomic {
+                        info!("Rolling back transaction on {}", id);
+                        //the restored store does not know about earlier unsaved changes, map_mut() flags it as changed
+                        *store = history::restore(&snapshot)?;
+                    }
+                    return Err(err);
+                }
+            };
+            //a change the client is told has failed may not be saved later, nor be missing from the log
+            let position = journal.position();
+            let revision = journal.append(store, &change).and_then(|_| {
+                history
+                    .as_ref()
+                    .map(|history| history.record(store, change, user))
+                    .transpose()
+            });
+            match revision {
+                Ok(revision) => Ok((result, revision)),
+                Err(err) => {
+                    warn!("Undoing a change to {} that could not be logged", id);
+                    journal.rollback(position)?;
+                    *store = history::restore(&snapshot)?;
+                    Err(err)
+                }
+            }
+        })
+    }
+
     /// Create a new store
     pub fn new_store(&self, id: &str) -> Result<(), ApiError> {
         if self.readonly {
             return Err(ApiError::PermissionDenied("Service is readonly"));
         }
-        self.check_basename(id)?;
+        let storefile = self.storefile(id)?;
         let filename: String = format!("{}.{}", id, self.extension());
-        let filename_pb: PathBuf = filename.clone().into();
-        if filename_pb.exists() {
+        if storefile.exists() {
             Err(ApiError::PermissionDenied("Store already exists"))
         } else {
+            //stores may be created in subdirectories that don't exist yet
+            if let Some(dir) = storefile.parent() {
+                std::fs::create_dir_all(dir)
+                    .map_err(|_| ApiError::InternalError("Unable to create directory"))?;
+            }
             let mut store = AnnotationStore::new(self.config.clone()).with_id(id);
             store.set_filename(filename.as_str());
             let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
@@ -152,6 +340,10 @@ impl StorePool {
                         last_access: now,
                         loading: false,
                         saving: false,
+                        //not written to disk yet
+                        changed: true,
+                        modified: None,
+                        conflict: false,
                     },
                 );
             } else {
@@ -159,6 +351,7 @@ impl StorePool {
             }
             if let Ok(mut stores) = self.stores.write() {
                 stores.insert(id.to_string(), Arc::new(RwLock::new(store)));
+                self.cache.invalidate(id);
                 self.add_webannoconfig(id);
             } else {
                 return Err(ApiError::InternalError("Lock poisoned"));
@@ -200,6 +393,7 @@ impl StorePool {
         store_id: &str,
         resource_id: &str,
         text: String,
+        user: Option<&str>,
     ) -> Result<(), ApiError> {
         if self.readonly {
             return Err(ApiError::PermissionDenied("Service is readonly"));
@@ -214,7 +408,7 @@ impl StorePool {
         if filename_pb.exists() {
             Err(ApiError::PermissionDenied("Resource already exists"))
         } else {
-            self.map_mut(store_id, |store| {
+            self.map_mut_recorded(store_id, user, |store| {
                 store
                     .add_resource(
                         TextResourceBuilder::new()
@@ -222,12 +416,200 @@ impl StorePool {
                             .with_text(text)
                             .with_filename(filename),
                     )
-                    .map(|_| ())
-                    .map_err(|e| ApiError::StamError(e))
+                    .map(|_| {
+                        (
+                            (),
+                            Change::Resource {
+                                id: resource_id.to_string(),
+                            },
+                        )
+                    })
+                    .map_err(ApiError::StamError)
             })
         }
     }
 
+    /// Returns the filename where the saved queries for a store are kept (alongside the store itself)
+    fn savedqueries_filename(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        let filename = self.basedir.join(&basename).with_extension(&self.extension);
+        if !filename.exists() {
+            return Err(ApiError::NotFound("No such annotationstore exists"));
+        }
+        Ok(self.basedir.join(basename).with_extension("queries.json"))
+    }
+
+    /// Returns all saved queries for a store
+    pub fn saved_queries(&self, id: &str) -> Result<BTreeMap<String, SavedQuery>, ApiError> {
+        savedqueries::read(&self.savedqueries_filename(id)?)
+    }
+
+    /// Returns a saved query by name
+    pub fn saved_query(&self, id: &str, name: &str) -> Result<SavedQuery, ApiError> {
+        self.saved_queries(id)?
+            .remove(name)
+            .ok_or(ApiError::NotFound("No such saved query"))
+    }
+
+    /// Adds or replaces a saved query
+    pub fn save_query(&self, id: &str, name: &str, query: SavedQuery) -> Result<(), ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        savedqueries::check_name(name)?;
+        query.validate()?;
+        let filename = self.savedqueries_filename(id)?;
+        let _lock = self
+            .savedqueries_lock
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?;
+        let mut queries = savedqueries::read(&filename)?;
+        queries.insert(name.to_string(), query);
+        savedqueries::write(&filename, &queries)
+    }
+
+    /// Removes a saved query
+    pub fn delete_query(&self, id: &str, name: &str) -> Result<(), ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        let filename = self.savedqueries_filename(id)?;
+        let _lock = self
+            .savedqueries_lock
+            .lock()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?;
+        let mut queries = savedqueries::read(&filename)?;
+        if queries.remove(name).is_none() {
+            return Err(ApiError::NotFound("No such saved query"));
+        }
+        savedqueries::write(&filename, &queries)
+    }
+
+    /// Returns the filename where the metadata of a store is kept (alongside the store itself)
+    fn metadata_filename(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        let filename = self.basedir.join(&basename).with_extension(&self.extension);
+        if !filename.exists() {
+            return Err(ApiError::NotFound("No such annotationstore exists"));
+        }
+        Ok(self.basedir.join(basename).with_extension("metadata.json"))
+    }
+
+    /// Returns the metadata of a store, empty if it has none
+    pub fn metadata(&self, id: &str) -> Result<StoreMetadata, ApiError> {
+        Ok(metadata::read(&self.metadata_filename(id)?)?.unwrap_or_default())
+    }
+
+    /// Replaces the metadata of a store, empty metadata removes it
+    pub fn set_metadata(&self, id: &str, metadata: StoreMetadata) -> Result<(), ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        metadata.validate()?;
+        metadata::write(&self.metadata_filename(id)?, &metadata)
+    }
+
+    /// Returns the history of a store, which is kept in a directory alongside the store itself
+    fn history(&self, id: &str) -> Result<History, ApiError> {
+        if !self.history {
+            return Err(ApiError::NotFound(
+                "History is not enabled for this service",
+            ));
+        }
+        let basename: PathBuf = self.check_store_id(id)?;
+        let filename = self.basedir.join(&basename).with_extension(&self.extension);
+        //newly created stores may not have been written to disk yet
+        let loaded = self
+            .stores
+            .read()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?
+            .contains_key(id);
+        if !loaded && !filename.exists() {
+            return Err(ApiError::NotFound("No such annotationstore exists"));
+        }
+        Ok(History::new(
+            self.basedir.join(basename).with_extension("history"),
+        ))
+    }
+
+    /// Returns the file a store is saved to
+    fn storefile(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        Ok(self.basedir.join(basename).with_extension(&self.extension))
+    }
+
+    /// Returns the write-ahead log of a store, which is kept alongside the store itself
+    fn journal(&self, id: &str) -> Result<Journal, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        Ok(Journal::new(
+            self.basedir.join(&basename).with_extension("wal"),
+            self.basedir.join(basename).with_extension(&self.extension),
+        ))
+    }
+
+    /// Returns the backups of a store, which are kept alongside the store itself
+    fn backups(&self, id: &str) -> Result<Backups, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        let base = self.basedir.join(&basename);
+        Ok(Backups::new(
+            base.with_extension(&self.extension),
+            base,
+            self.backups,
+            &self.basedir,
+        ))
+    }
+
+    /// Returns all backups of a store, oldest first
+    pub fn list_backups(&self, id: &str) -> Result<Vec<Backup>, ApiError> {
+        let basename: PathBuf = self.check_store_id(id)?;
+        if !self
+            .basedir
+            .join(basename)
+            .with_extension(&self.extension)
+            .exists()
+        {
+            return Err(ApiError::NotFound("No such annotationstore exists"));
+        }
+        self.backups(id)?.list()
+    }
+
+    /// Restores a store from a backup. Like any other change, this is only written to the store file when the store is saved,
+    /// so the version it replaces becomes a backup in turn.
+    pub fn restore_backup(
+        &self,
+        id: &str,
+        backup: u64,
+        user: Option<&str>,
+    ) -> Result<(), ApiError> {
+        let backups = self.backups(id)?;
+        self.map_mut_recorded(id, user, |store| {
+            *store = backups.load(backup, store)?;
+            Ok(((), Change::Restore { backup }))
+        })
+    }
+
+    /// Returns all recorded revisions of a store, oldest first
+    pub fn revisions(&self, id: &str) -> Result<Vec<Revision>, ApiError> {
+        self.history(id)?.revisions()
+    }
+
+    /// Reverts a store to the state of an earlier revision, this is recorded as a new revision
+    pub fn revert(
+        &self,
+        id: &str,
+        revision: usize,
+        user: Option<&str>,
+    ) -> Result<Revision, ApiError> {
+        let history = self.history(id)?;
+        let (_, revision) = self.apply_recorded(id, user, false, |store| {
+            let state = history.state(revision)?;
+            check_files(&self.basedir, &state)?;
+            *store = state;
+            Ok(((), Change::Revert { revision }))
+        })?;
+        revision.ok_or(ApiError::InternalError("Revert was not recorded"))
+    }
+
     /// Loads an annotation store if it is not already loaded.
     /// Only one thread can load at a time.
     /// This function blocks until the store is loaded (either by us or by another thread)
@@ -268,7 +650,7 @@ impl StorePool {
         }
 
         //some security checks so the user can't break out of the configured base directory
-        let basename: PathBuf = self.check_basename(id)?;
+        let basename: PathBuf = self.check_store_id(id)?;
 
         let filename = self
             .basedir
@@ -288,6 +670,10 @@ impl StorePool {
                     last_access: now,
                     loading: true,
                     saving: false,
+                    changed: false,
+                    //taken before reading, so a change on disk during loading is detected later
+                    modified: wal::modified(&filename),
+                    conflict: false,
                 },
             );
         } else {
@@ -295,38 +681,59 @@ impl StorePool {
         }
 
         //note the actual store loading (time intensive) done here is done without any locks held
-        if let Some(filename) = filename.to_str() {
-            info!("Loading {}", id);
-            match AnnotationStore::from_file(filename, self.config.clone()) {
-                Ok(store) => {
-                    //TODO: verify substores and resources can't break out of the base dir either!
-                    if let Ok(mut stores) = self.stores.write() {
-                        stores.insert(id.to_string(), Arc::new(RwLock::new(store)));
-                        self.add_webannoconfig(id);
-                    } else {
-                        return Err(ApiError::InternalError("Lock poisoned"));
-                    }
-                }
-                Err(e) => {
-                    return Err(ApiError::StamError(e));
+        let replayed = match self.load_file(id, &filename) {
+            Ok(replayed) => replayed,
+            Err(e) => {
+                //forget about the store again so others don't wait for it forever
+                if let Ok(mut states) = self.states.write() {
+                    states.remove(id);
                 }
+                return Err(e);
             }
-        } else {
-            return Err(ApiError::NotFound(
-                "No such annotationstore exists (invalid unicode)",
-            ));
-        }
+        };
 
         //mark loading as done:
         if let Ok(mut states) = self.states.write() {
             if let Some(state) = states.get_mut(id) {
                 state.loading = false;
+                state.changed = replayed > 0;
                 Ok(state.clone())
             } else {
-                return Err(ApiError::InternalError("State must exist"));
+                Err(ApiError::InternalError("State must exist"))
             }
         } else {
-            return Err(ApiError::InternalError("Lock poisoned"));
+            Err(ApiError::InternalError("Lock poisoned"))
+        }
+    }
+
+    /// Loads a store from file and replays any changes that were not saved yet, returns the number of replayed changes
+    fn load_file(&self, id: &str, filename: &Path) -> Result<usize, ApiError> {
+        if let Some(filename) = filename.to_str() {
+            info!("Loading {}", id);
+            check_store_file(&self.basedir, Path::new(filename))?;
+            let mut store = AnnotationStore::from_file(filename, self.config.clone())?;
+            //apply any changes that were not saved yet
+            let history = if self.history {
+                Some(self.history(id)?)
+            } else {
+                None
+            };
+            let replayed =
+                self.journal(id)?
+                    .replay(&mut store, history.as_ref(), &self.backups(id)?)?;
+            check_files(&self.basedir, &store)?;
+            if let Ok(mut stores) = self.stores.write() {
+                stores.insert(id.to_string(), Arc::new(RwLock::new(store)));
+                self.cache.invalidate(id);
+                self.add_webannoconfig(id);
+            } else {
+                return Err(ApiError::InternalError("Lock poisoned"));
+            }
+            Ok(replayed)
+        } else {
+            Err(ApiError::NotFound(
+                "No such annotationstore exists (invalid unicode)",
+            ))
         }
     }
 
@@ -355,18 +762,20 @@ impl StorePool {
     /// Save an annotation store to disk if there are any changes
     /// Will return an error if the store is not loaded
     pub fn save(&self, id: &str) -> Result<(), ApiError> {
-        self.wait_until_ready(id)?;
-
         if self.readonly {
             return Err(ApiError::PermissionDenied(
                 "Service is configured as read-only",
             ));
         }
 
-        //mark in progress
+        self.start_saving(id)?;
+
+        let result = self.save_changes(id);
+
+        //mark done
         if let Ok(mut states) = self.states.write() {
             if let Some(state) = states.get_mut(id) {
-                state.saving = true;
+                state.saving = false;
             } else {
                 return Err(ApiError::InternalError("State must exist"));
             }
@@ -374,64 +783,461 @@ impl StorePool {
             return Err(ApiError::InternalError("Lock poisoned"));
         }
 
+        result
+    }
+
+    /// Marks a store as being saved, waiting for any other thread that is loading or saving it.
+    /// Checking and marking happen under the same lock, so only one thread at a time can save a store.
+    fn start_saving(&self, id: &str) -> Result<(), ApiError> {
+        loop {
+            if let Ok(mut states) = self.states.write() {
+                match states.get_mut(id) {
+                    Some(state) if !state.loading && !state.saving => {
+                        state.saving = true;
+                        return Ok(());
+                    }
+                    Some(_) => {}
+                    None => return Err(ApiError::NotFound("No such store loaded")),
+                }
+            } else {
+                return Err(ApiError::InternalError("Lock poisoned"));
+            }
+            std::thread::sleep(WAIT_INTERVAL);
+        }
+    }
+
+    /// Writes a store to disk if it has any changes, the store must be marked as being saved
+    fn save_changes(&self, id: &str) -> Result<(), ApiError> {
+        let mut result = Ok(());
         if let Ok(stores) = self.stores.read() {
             if let Some(store) = stores.get(id).cloned() {
                 drop(stores); //compiler should be able to infer this, but better safe than sorry
                 if let Ok(store) = store.read() {
                     //read lock held during saving, so nothing else can write
-                    if store.changed() {
-                        info!("Saving {}", id);
-                        store.save()?;
+                    if self.changed(id)? || store.changed() {
+                        //never overwrite a version that someone else wrote in the meantime
+                        result = if self.changed_on_disk(id)? {
+                            self.mark_conflict(id, &store)
+                        } else {
+                            info!("Saving {}", id);
+                            self.backups(id)?
+                                .save(&store)
+                                .and_then(|_| self.journal(id)?.truncate())
+                        };
+                        if result.is_ok() {
+                            self.mark_saved(id)?;
+                        }
                     }
                 }
             }
         }
+        result
+    }
 
-        //mark done
+    /// Clears the flag for unsaved changes, must be called while holding a lock on the store
+    fn mark_saved(&self, id: &str) -> Result<(), ApiError> {
+        let modified = wal::modified(&self.storefile(id)?);
         if let Ok(mut states) = self.states.write() {
             if let Some(state) = states.get_mut(id) {
-                state.saving = false;
-            } else {
-                return Err(ApiError::InternalError("State must exist"));
+                state.changed = false;
+                state.modified = modified;
             }
+            Ok(())
         } else {
-            return Err(ApiError::InternalError("Lock poisoned"));
+            Err(ApiError::InternalError("Lock poisoned"))
+        }
+    }
+
+    /// Returns whether a store has changes that have not been saved yet
+    fn changed(&self, id: &str) -> Result<bool, ApiError> {
+        if let Ok(states) = self.states.read() {
+            Ok(states.get(id).is_some_and(|state| state.changed))
+        } else {
+            Err(ApiError::InternalError("Lock poisoned"))
         }
+    }
 
-        Ok(())
+    /// Returns whether the store file was changed on disk since the store was loaded or last saved
+    fn changed_on_disk(&self, id: &str) -> Result<bool, ApiError> {
+        let modified = wal::modified(&self.storefile(id)?);
+        if let Ok(states) = self.states.read() {
+            Ok(states
+                .get(id)
+                .is_some_and(|state| state.modified != modified))
+        } else {
+            Err(ApiError::InternalError("Lock poisoned"))
+        }
     }
 
-    /// Unload an annotation store if it is loaded (no-op if it isn't loaded)
-    pub fn unload(&self, id: &str) -> Result<(), ApiError> {
-        match self.wait_until_ready(id) {
-            Ok(_) => {
-                if !self.readonly {
-                    self.save(id)?;
+    /// Flags a store whose file was changed on disk while it had unsaved changes. The store as it is in memory
+    /// is kept as a backup, so the changes are not lost when the store is reloaded. Must be called while holding a lock on the store.
+    /// Always returns a conflict error.
+    fn mark_conflict(&self, id: &str, store: &AnnotationStore) -> Result<(), ApiError> {
+        let flagged = if let Ok(mut states) = self.states.write() {
+            states
+                .get_mut(id)
+                .is_some_and(|state| !std::mem::replace(&mut state.conflict, true))
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        };
+        if flagged {
+            match self.backups(id)?.keep_copy(store) {
+                Ok(backup) => warn!(
+                    "{} was changed on disk while it had unsaved changes, these are kept as backup {}",
+                    id, backup
+                ),
+                Err(e) => error!(
+                    "{} was changed on disk while it had unsaved changes, and these could not be kept as a backup: {:?}",
+                    id, e
+                ),
+            }
+        }
+        Err(ApiError::Conflict(CONFLICT))
+    }
+
+    /// Checks whether the files of loaded stores were changed on disk (e.g. by another program). Stores without unsaved changes
+    /// are unloaded, so the new version is loaded when they are used again. Stores with unsaved changes are flagged as conflicted:
+    /// they can not be changed or saved until they are reloaded. Returns the stores that were changed on disk.
+    pub fn check_changes(&self) -> Result<Vec<String>, ApiError> {
+        let ids: Vec<String> = if let Ok(states) = self.states.read() {
+            states.keys().cloned().collect()
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        };
+
+        let mut changed_ids: Vec<String> = Vec::new();
+        for id in ids.into_iter() {
+            let storefile = self.storefile(&id)?;
+            //the state and the file are compared under one lock: a store that is being saved is newer on disk than its state says,
+            //and a save that finishes in the meantime updates the state
+            let changed = if let Ok(states) = self.states.read() {
+                match states.get(&id) {
+                    Some(state)
+                        if !state.loading
+                            && !state.saving
+                            && !state.conflict
+                            && state.modified != wal::modified(&storefile) =>
+                    {
+                        state.changed
+                    }
+                    _ => continue,
                 }
-                if let Ok(mut stores) = self.stores.write() {
-                    if stores.contains_key(id) {
-                        stores.remove(id);
+            } else {
+                return Err(ApiError::InternalError("Lock poisoned"));
+            };
+            let result = if changed {
+                self.map(&id, |store| self.mark_conflict(&id, store))
+            } else {
+                info!("{} was changed on disk, unloading it", id);
+                self.unload(&id)
+            };
+            match result {
+                //conflicts are flagged and logged already, stores may have been unloaded in the meantime
+                Ok(()) | Err(ApiError::Conflict(_)) | Err(ApiError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+            changed_ids.push(id);
+        }
+
+        Ok(changed_ids)
+    }
+
+    /// Returns all available stores, in the base directory and its subdirectories, sorted by identifier.
+    /// Stores that are loaded also report whether they have unsaved changes and how many items they hold. This never loads any stores.
+    pub fn list_stores(&self) -> Result<Vec<StoreInfo>, ApiError> {
+        let mut stores: Vec<StoreInfo> = Vec::new();
+        find_stores(
+            &self.basedir,
+            "",
+            &format!(".{}", self.extension),
+            &mut stores,
+        )?;
+        stores.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let loaded: HashMap<String, Arc<RwLock<AnnotationStore>>> =
+            if let Ok(loaded) = self.stores.read() {
+                loaded.clone()
+            } else {
+                return Err(ApiError::InternalError("Lock poisoned"));
+            };
+        let states = if let Ok(states) = self.states.read() {
+            states.clone()
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        };
+        for info in stores.iter_mut() {
+            match (states.get(&info.id), loaded.get(&info.id)) {
+                (Some(state), Some(store)) if !state.loading => {
+                    info.loaded = true;
+                    info.changed = Some(state.changed);
+                    if let Ok(store) = store.read() {
+                        info.annotations = Some(store.annotations_len());
+                        info.resources = Some(store.resources_len());
+                        info.datasets = Some(store.datasets_len());
                     }
-                } else {
-                    return Err(ApiError::InternalError("Lock poisoned"));
                 }
+                _ => {}
+            }
+        }
+        Ok(stores)
+    }
+
+    /// Copies a store to a new store. The copy is made from the store as it is in memory, so it includes any unsaved changes.
+    /// Its metadata and saved queries are copied along, its history and backups are not.
+    pub fn copy(&self, id: &str, to: &str) -> Result<(), ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        if id == to {
+            return Err(ApiError::InvalidArgument(
+                "A store can not be copied onto itself",
+            ));
+        }
+        self.map(id, |store| self.write_copy(store, id, to))?;
+        info!("Copied {} to {}", id, to);
+        Ok(())
+    }
+
+    /// Renames a store, along with its metadata, saved queries, history and backups. Unsaved changes are kept.
+    pub fn rename(&self, id: &str, to: &str) -> Result<(), ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        if id == to {
+            return Err(ApiError::InvalidArgument(
+                "A store can not be renamed to itself",
+            ));
+        }
+        self.check_new_store(to)?;
+        if self.load(id)?.conflict {
+            return Err(ApiError::Conflict(CONFLICT));
+        }
+
+        //flag the store as loading so nothing else uses it in the meantime, anyone who waits for it finds it gone afterwards
+        self.set_loading(id, true)?;
+        match self.move_store(id, to) {
+            Ok(()) => {
+                self.remove(id)?;
+                info!("Renamed {} to {}", id, to);
+                Ok(())
+            }
+            Err(e) => {
+                self.set_loading(id, false)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Merges the resources, data and annotations of the source store into a store, as a single change that is rolled back entirely if it fails.
+    /// The source store is left as it is.
+    pub fn merge(
+        &self,
+        id: &str,
+        source: &str,
+        collisions: Collisions,
+        user: Option<&str>,
+    ) -> Result<MergeReport, ApiError> {
+        if self.readonly {
+            return Err(ApiError::PermissionDenied("Service is readonly"));
+        }
+        if id == source {
+            return Err(ApiError::InvalidArgument(
+                "A store can not be merged into itself",
+            ));
+        }
+        //the source is detached first, so the two stores are never locked at once
+        let sourcedata = self.map(source, merge::Source::extract)?;
+        let report = self.transaction(id, user, |store| {
+            let (plan, report) = merge::plan(&sourcedata, store, collisions)?;
+            plan.apply(store)?;
+            Ok((
+                report.clone(),
+                Change::Merge {
+                    source: source.to_string(),
+                    report: Box::new(report),
+                    plan: Box::new(plan),
+                },
+            ))
+        })?;
+        info!("Merged {} into {}", source, id);
+        Ok(report)
+    }
+
+    fn set_loading(&self, id: &str, loading: bool) -> Result<(), ApiError> {
+        if let Ok(mut states) = self.states.write() {
+            if let Some(state) = states.get_mut(id) {
+                state.loading = loading;
+                Ok(())
+            } else {
+                Err(ApiError::NotFound("No such store loaded"))
+            }
+        } else {
+            Err(ApiError::InternalError("Lock poisoned"))
+        }
+    }
 
-                if let Ok(mut webannoconfigs) = self.webannoconfigs.write() {
-                    if webannoconfigs.contains_key(id) {
-                        webannoconfigs.remove(id);
+    /// Copies a loaded store to its new name and removes the files of the old one
+    fn move_store(&self, id: &str, to: &str) -> Result<(), ApiError> {
+        let store = if let Ok(stores) = self.stores.read() {
+            stores
+                .get(id)
+                .cloned()
+                .ok_or(ApiError::InternalError("Annotationstore not loaded"))?
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned: stores"));
+        };
+        //waits for anything that is still using the store
+        let store = store
+            .write()
+            .map_err(|_| ApiError::InternalError("Store lock got poisoned"))?;
+        //the old store file is removed, which must not discard a version someone else saved in the meantime
+        if self.changed_on_disk(id)? {
+            return Err(ApiError::Conflict(
+                "The store was changed on disk since it was loaded, it must be reloaded first",
+            ));
+        }
+        self.write_copy(&store, id, to)?;
+
+        self.backups(id)?.move_to(&self.backups(to)?)?;
+        let base = self.basedir.join(self.check_store_id(id)?);
+        let history = base.with_extension("history");
+        if history.exists() {
+            std::fs::rename(
+                history,
+                self.basedir
+                    .join(self.check_store_id(to)?)
+                    .with_extension("history"),
+            )
+            .map_err(|_| ApiError::InternalError("Unable to move history"))?;
+        }
+        //the unsaved changes in the journal are part of the copy
+        self.journal(id)?.truncate()?;
+        for filename in [
+            self.storefile(id)?,
+            base.with_extension("metadata.json"),
+            base.with_extension("queries.json"),
+        ] {
+            if filename.exists() {
+                std::fs::remove_file(filename)
+                    .map_err(|_| ApiError::InternalError("Unable to remove store"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that a store can be created under the given identifier, returns the file it will be saved to
+    fn check_new_store(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let storefile = self.storefile(id)?;
+        let loaded = self
+            .stores
+            .read()
+            .map_err(|_| ApiError::InternalError("Lock poisoned"))?
+            .contains_key(id);
+        if loaded || storefile.exists() {
+            Err(ApiError::PermissionDenied("Store already exists"))
+        } else {
+            Ok(storefile)
+        }
+    }
+
+    /// Writes a store to disk as a new store, along with its metadata and saved queries.
+    /// Copies in the same directory share the stand-off files of the store, copies elsewhere get their own.
+    fn write_copy(&self, store: &AnnotationStore, id: &str, to: &str) -> Result<(), ApiError> {
+        let storefile = self.check_new_store(to)?;
+        let data = backup::serialize_as(store, to, &storefile)?.ok_or(
+            ApiError::InvalidArgument("Stores in this format can not be copied"),
+        )?;
+        let targetdir = storefile.parent().unwrap_or(&self.basedir);
+
+        //serializing (above) wrote any pending stand-off files, so they are all on disk now
+        let mut copies: Vec<(PathBuf, PathBuf)> = Vec::new();
+        if let Some(sourcedir) = store_dir(store) {
+            if self.storefile(id)?.parent() != Some(targetdir) {
+                for file in standoff_files(store)? {
+                    if !file.exists() {
+                        //the contents are embedded in the store itself
+                        continue;
+                    }
+                    let target = targetdir.join(file.strip_prefix(&sourcedir).map_err(|_| {
+                        ApiError::InvalidArgument(
+                            "The store refers to files outside its own directory, it can only be copied within that directory",
+                        )
+                    })?);
+                    check_path(&self.basedir, &target)?;
+                    if target.exists() {
+                        if std::fs::read(&file).ok() != std::fs::read(&target).ok() {
+                            return Err(ApiError::Conflict(
+                                "A different file already exists in place of a stand-off file of the copy",
+                            ));
+                        }
+                    } else {
+                        copies.push((file, target));
                     }
-                } else {
-                    return Err(ApiError::InternalError("Lock poisoned"));
                 }
+            }
+        }
+        for (file, target) in copies {
+            if let Some(dir) = target.parent() {
+                std::fs::create_dir_all(dir)
+                    .map_err(|_| ApiError::InternalError("Unable to create directory"))?;
+            }
+            std::fs::copy(file, target)
+                .map_err(|_| ApiError::InternalError("Unable to copy stand-off file"))?;
+        }
 
-                if let Ok(mut states) = self.states.write() {
-                    if states.contains_key(id) {
-                        states.remove(id);
+        std::fs::create_dir_all(targetdir)
+            .map_err(|_| ApiError::InternalError("Unable to create directory"))?;
+        self.backups(to)?.replace(&data)?;
+        let source = self.basedir.join(self.check_store_id(id)?);
+        let target = self.basedir.join(self.check_store_id(to)?);
+        for extension in ["metadata.json", "queries.json"] {
+            let filename = source.with_extension(extension);
+            if filename.exists() {
+                std::fs::copy(filename, target.with_extension(extension))
+                    .map_err(|_| ApiError::InternalError("Unable to copy store"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards a store as it is in memory and loads it from disk again. This resolves conflicts.
+    /// Any unsaved changes are kept as a backup first (conflicted stores already have one), so they can still be restored.
+    pub fn reload(&self, id: &str) -> Result<(), ApiError> {
+        match self.wait_until_ready(id) {
+            Ok(_) => {
+                if let Ok(stores) = self.stores.read() {
+                    if let Some(store) = stores.get(id).cloned() {
+                        drop(stores);
+                        if let Ok(store) = store.write() {
+                            //write lock held so nothing can change while we discard the store
+                            let state = self.wait_until_ready(id)?;
+                            if state.changed && !state.conflict {
+                                let backup = self.backups(id)?.keep_copy(&store)?;
+                                warn!(
+                                    "Discarding unsaved changes to {}, these are kept as backup {}",
+                                    id, backup
+                                );
+                            }
+                            self.journal(id)?.truncate()?;
+                            self.remove(id)?;
+                        }
                     }
-                } else {
-                    return Err(ApiError::InternalError("Lock poisoned"));
                 }
+            }
+            Err(ApiError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        self.load(id).map(|_| ())
+    }
 
+    /// Unload an annotation store if it is loaded (no-op if it isn't loaded)
+    pub fn unload(&self, id: &str) -> Result<(), ApiError> {
+        match self.wait_until_ready(id) {
+            Ok(_) => {
+                if !self.readonly {
+                    self.save(id)?;
+                }
+                self.remove(id)?;
                 info!("Unloaded {}", id);
                 Ok(())
             }
@@ -440,6 +1246,35 @@ impl StorePool {
         }
     }
 
+    /// Forgets about a loaded store, without saving it
+    fn remove(&self, id: &str) -> Result<(), ApiError> {
+        self.cache.invalidate(id);
+        if let Ok(mut stores) = self.stores.write() {
+            if stores.contains_key(id) {
+                stores.remove(id);
+            }
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        }
+
+        if let Ok(mut webannoconfigs) = self.webannoconfigs.write() {
+            if webannoconfigs.contains_key(id) {
+                webannoconfigs.remove(id);
+            }
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        }
+
+        if let Ok(mut states) = self.states.write() {
+            if states.contains_key(id) {
+                states.remove(id);
+            }
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        }
+        Ok(())
+    }
+
     pub fn flush(&self, force: bool) -> Result<Vec<String>, ApiError> {
         let mut remove_ids: Vec<String> = Vec::new();
 
@@ -455,11 +1290,112 @@ impl StorePool {
             return Err(ApiError::InternalError("Lock poisoned"));
         }
 
-        for id in remove_ids.iter() {
-            self.unload(&id)?;
+        let mut unloaded_ids: Vec<String> = Vec::new();
+        for id in remove_ids.into_iter() {
+            match self.unload(&id) {
+                Ok(()) => unloaded_ids.push(id),
+                //conflicted stores stay loaded until they are reloaded, their changes are kept as a backup already
+                Err(ApiError::Conflict(_)) => {}
+                Err(e) => return Err(e),
+            }
         }
 
-        Ok(remove_ids)
+        Ok(unloaded_ids)
+    }
+
+    /// Saves all loaded stores that have unsaved changes, without unloading them. Returns the stores that were saved.
+    pub fn autosave(&self) -> Result<Vec<String>, ApiError> {
+        let mut save_ids: Vec<String> = Vec::new();
+
+        if let Ok(states) = self.states.read() {
+            for (id, state) in states.iter() {
+                if state.changed && !state.loading {
+                    save_ids.push(id.to_string());
+                }
+            }
+        } else {
+            return Err(ApiError::InternalError("Lock poisoned"));
+        }
+
+        let mut saved_ids: Vec<String> = Vec::new();
+        for id in save_ids.into_iter() {
+            match self.save(&id) {
+                Ok(()) => saved_ids.push(id),
+                //unloaded in the meantime (and saved while doing so), or flagged and logged already
+                Err(ApiError::NotFound(_)) | Err(ApiError::Conflict(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(saved_ids)
+    }
+
+    /// Finds a store in a subdirectory that is addressed by a request path such as `/project/subcorpus/x/annotations`.
+    /// Leading path segments that name subdirectories of the base directory are taken to be part of the store identifier,
+    /// unless a store by that name exists as well. Returns the path with the store identifier as a single (percent-encoded)
+    /// segment, e.g. `/project%2Fsubcorpus%2Fx/annotations`, or `None` if the path does not address a store in a subdirectory.
+    pub fn resolve_nested_path(&self, path: &str) -> Option<String> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut dir = self.basedir.clone();
+        let mut depth = 0;
+        while depth + 1 < segments.len() {
+            let segment = segments[depth];
+            //percent-encoded segments are never directories, this also keeps encoded slashes and dots out
+            if segment.is_empty() || segment.starts_with('.') || segment.contains('%') {
+                break;
+            }
+            let candidate = dir.join(segment);
+            if !candidate.is_dir() || dir.join(format!("{}.{}", segment, self.extension)).exists() {
+                break;
+            }
+            dir = candidate;
+            depth += 1;
+        }
+        if depth == 0 {
+            None
+        } else {
+            Some(
+                format!(
+                    "/{}/{}",
+                    segments[..=depth].join("%2F"),
+                    segments[depth + 1..].join("/")
+                )
+                .trim_end_matches('/')
+                .to_string(),
+            )
+        }
+    }
+
+    /// Checks a store identifier and returns it as a path relative to the base directory. Stores may be organised in
+    /// subdirectories, so the identifier may consist of multiple components (e.g. `project/subcorpus/x`).
+    fn check_store_id(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let filename: PathBuf = id.into();
+
+        //some security checks so the user can't break out of the configured base directory
+        if filename.is_absolute() {
+            return Err(ApiError::NotFound(
+                "No such annotationstore exists (no absolute paths allowed)",
+            ));
+        }
+        for component in filename.components() {
+            match component {
+                Component::Normal(_) => {}
+                Component::ParentDir => {
+                    return Err(ApiError::NotFound(
+                        "No such annotationstore exists (no parent directories allowed)",
+                    ))
+                }
+                _ => {
+                    return Err(ApiError::NotFound(
+                        "No such annotationstore exists (invalid path)",
+                    ))
+                }
+            }
+        }
+        if filename.components().next().is_none() {
+            return Err(ApiError::NotFound("No such annotationstore exists"));
+        }
+        Ok(filename)
     }
 
     fn check_basename(&self, id: &str) -> Result<PathBuf, ApiError> {
@@ -485,6 +1421,280 @@ impl StorePool {
     }
 }
 
+/// Finds all stores in a directory and its subdirectories, the identifiers of stores in subdirectories are prefixed with their path
+fn find_stores(
+    dir: &Path,
+    prefix: &str,
+    extension: &str,
+    stores: &mut Vec<StoreInfo>,
+) -> Result<(), ApiError> {
+    for entry in std::fs::read_dir(dir)
+        .map_err(|_| ApiError::InternalError("Unable to read base directory"))?
+    {
+        let entry = entry.map_err(|_| ApiError::InternalError("Unable to read base directory"))?;
+        if let Some(filename) = entry.file_name().to_str() {
+            //symlinked directories are not followed, hidden and history directories are skipped
+            if entry.file_type().is_ok_and(|filetype| filetype.is_dir()) {
+                if !filename.starts_with('.') && !filename.ends_with(".history") {
+                    find_stores(
+                        &entry.path(),
+                        &format!("{}{}/", prefix, filename),
+                        extension,
+                        stores,
+                    )?;
+                }
+            } else if let Some(id) = filename.strip_suffix(extension).filter(|id| !id.is_empty()) {
+                let metadata = entry.metadata().ok();
+                stores.push(StoreInfo {
+                    id: format!("{}{}", prefix, id),
+                    size: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+                    modified: metadata
+                        .and_then(|m| m.modified().ok())
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    loaded: false,
+                    changed: None,
+                    annotations: None,
+                    resources: None,
+                    datasets: None,
+                    //a broken metadata file should not prevent listing
+                    metadata: metadata::read(&dir.join(format!("{}.metadata.json", id)))
+                        .ok()
+                        .flatten(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that all files a store refers to are within the base directory, see [`standoff_files()`]
+pub(crate) fn check_files(basedir: &Path, store: &AnnotationStore) -> Result<(), ApiError> {
+    for path in standoff_files(store)? {
+        check_path(basedir, &path)?;
+    }
+    Ok(())
+}
+
+/// Checks a store file and all files it includes before any of them are loaded, so nothing outside the base directory is read,
+/// and nothing that is not a regular file (a FIFO or a device would block or exhaust memory).
+/// A STAM CSV store is a manifest listing its files, STAM CBOR is self-contained.
+fn check_store_file(basedir: &Path, filename: &Path) -> Result<(), ApiError> {
+    let storedir = filename.parent();
+    if filename
+        .extension()
+        .is_some_and(|extension| extension == "csv")
+    {
+        check_included_files(basedir, vec![filename.to_path_buf()], storedir)?;
+        let mut reader = csv::Reader::from_path(filename)
+            .map_err(|_| ApiError::InternalError("Unable to read the store"))?;
+        let column = reader
+            .headers()
+            .map_err(|_| ApiError::InternalError("Unable to read the store"))?
+            .iter()
+            .position(|header| header == "Filename");
+        let mut files = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(|_| ApiError::InternalError("Unable to read the store"))?;
+            if let Some(filename) = column.and_then(|column| record.get(column)) {
+                files.push(resolve_path(filename, storedir));
+            }
+        }
+        check_included_files(basedir, files, storedir)
+    } else {
+        check_included_files(basedir, vec![filename.to_path_buf()], storedir)
+    }
+}
+
+/// Like [`check_store_file()`], for a store in STAM JSON that is loaded from memory with the given working directory
+pub(crate) fn check_store_json(
+    basedir: &Path,
+    json: &str,
+    workdir: Option<&Path>,
+) -> Result<(), ApiError> {
+    //invalid JSON is refused by STAM later
+    if let Ok(value) = serde_json::from_str(json) {
+        let mut files = Vec::new();
+        included_files(&value, &[workdir], &mut files);
+        check_included_files(basedir, files, workdir)?;
+    }
+    Ok(())
+}
+
+/// Checks the given files, and recursively the files included by those STAM may parse as STAM JSON.
+/// Resources and datasets are included relative to the store directory, substores relative to the file that includes them,
+/// both are checked as we can not tell them apart without parsing the store the way STAM does.
+fn check_included_files(
+    basedir: &Path,
+    mut pending: Vec<PathBuf>,
+    storedir: Option<&Path>,
+) -> Result<(), ApiError> {
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    while let Some(path) = pending.pop() {
+        check_path(basedir, &path)?;
+        //files that do not exist are not read either, STAM reports them
+        let Ok(resolved) = path.canonicalize() else {
+            continue;
+        };
+        if !seen.insert(resolved.clone()) {
+            continue;
+        }
+        if !resolved.is_file() {
+            warn!("Refusing to load {}, it is not a file", path.display());
+            return Err(ApiError::PermissionDenied(
+                "The store refers to something that is not a regular file",
+            ));
+        }
+        //plain text resources and stand-off files in other formats can not include anything
+        if resolved
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| matches!(extension, "txt" | "md" | "csv" | "cbor"))
+        {
+            continue;
+        }
+        let data = std::fs::read(&resolved)
+            .map_err(|_| ApiError::InternalError("Unable to read an included file"))?;
+        if let Ok(value) = serde_json::from_slice(&data) {
+            included_files(&value, &[resolved.parent(), storedir], &mut pending);
+        }
+    }
+    Ok(())
+}
+
+/// Collects the files included (`@include`) anywhere in STAM JSON, resolved relative to each of the given directories
+fn included_files(value: &serde_json::Value, dirs: &[Option<&Path>], files: &mut Vec<PathBuf>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let filenames = match map.get("@include") {
+                Some(serde_json::Value::String(filename)) => vec![filename.as_str()],
+                Some(serde_json::Value::Array(filenames)) => filenames
+                    .iter()
+                    .filter_map(|filename| filename.as_str())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            for filename in filenames {
+                for dir in dirs {
+                    files.push(resolve_path(filename, *dir));
+                }
+            }
+            for value in map.values() {
+                included_files(value, dirs, files);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                included_files(value, dirs, files);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the directory of a store, relative to which it refers to other files
+fn store_dir(store: &AnnotationStore) -> Option<PathBuf> {
+    store
+        .config()
+        .workdir()
+        .map(|workdir| workdir.to_path_buf())
+        .or_else(|| store.dirname())
+}
+
+/// Returns all files a store refers to: the stand-off files of resources and datasets, and included substores (`@include`)
+fn standoff_files(store: &AnnotationStore) -> Result<Vec<PathBuf>, ApiError> {
+    let storedir = store_dir(store);
+    let storedir = storedir.as_deref();
+    let mut files = Vec::new();
+    for resource in store.resources() {
+        if let Some(filename) = resource.as_ref().filename() {
+            let workdir = resource.as_ref().config().workdir().or(storedir);
+            files.push(resolve_path(filename, workdir));
+        }
+    }
+    for dataset in store.datasets() {
+        if let Some(filename) = dataset.as_ref().filename() {
+            let workdir = dataset.as_ref().config().workdir().or(storedir);
+            files.push(resolve_path(filename, workdir));
+        }
+    }
+    for substore in store.substores_flatten() {
+        files.push(substore_path(store, storedir, substore.as_ref()).ok_or(
+            ApiError::PermissionDenied("Unable to resolve the file of an included store"),
+        )?);
+    }
+    Ok(files)
+}
+
+/// Resolves the file of a substore. Substores are included relative to the store (or substore) that includes them.
+fn substore_path(
+    store: &AnnotationStore,
+    storedir: Option<&Path>,
+    substore: &AnnotationSubStore,
+) -> Option<PathBuf> {
+    let filename = substore.filename()?.to_str()?;
+    //the first parent is the one the substore was loaded from, others merely include it again
+    match substore.parents().first().copied().flatten() {
+        Some(parent) => {
+            let parent = substore_path(store, storedir, store.substore(parent)?.as_ref())?;
+            Some(resolve_path(filename, parent.parent()))
+        }
+        None => Some(resolve_path(filename, storedir)),
+    }
+}
+
+/// Resolves a filename the way STAM does: absolute paths are taken as is, relative ones are relative to the working directory
+fn resolve_path(filename: &str, workdir: Option<&Path>) -> PathBuf {
+    let filename = Path::new(filename.strip_prefix("file://").unwrap_or(filename));
+    match workdir {
+        Some(workdir) if filename.is_relative() => workdir.join(filename),
+        _ => filename.to_path_buf(),
+    }
+}
+
+/// Checks that a path is within the base directory, after resolving any `..` components and symbolic links.
+/// The path itself need not exist yet.
+fn check_path(basedir: &Path, path: &Path) -> Result<(), ApiError> {
+    let basedir = basedir
+        .canonicalize()
+        .map_err(|_| ApiError::InternalError("Unable to resolve the base directory"))?;
+    let mut path = if path.is_relative() {
+        std::env::current_dir()
+            .map_err(|_| ApiError::InternalError("Unable to resolve the current directory"))?
+            .join(path)
+    } else {
+        path.to_path_buf()
+    };
+    //resolve the part of the path that exists, the (non-existing) remainder may not refer to any parent directories
+    let mut remainder: Vec<std::ffi::OsString> = Vec::new();
+    let resolved = loop {
+        match path.canonicalize() {
+            Ok(resolved) => break Some(resolved),
+            Err(_) => match (path.file_name(), path.parent()) {
+                (Some(name), Some(parent)) => {
+                    remainder.push(name.to_owned());
+                    path = parent.to_path_buf();
+                }
+                _ => break None,
+            },
+        }
+    };
+    match resolved {
+        Some(resolved) if resolved.starts_with(&basedir) => Ok(()),
+        _ => {
+            warn!(
+                "Refusing to load {}, it is outside the base directory",
+                path.join(remainder.iter().rev().collect::<PathBuf>())
+                    .display()
+            );
+            Err(ApiError::PermissionDenied(
+                "The store refers to a file outside the base directory",
+            ))
+        }
+    }
+}
+
 impl Drop for StorePool {
     fn drop(&mut self) {
         if !self.readonly {
@@ -492,3 +1702,257 @@ impl Drop for StorePool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// Sets up a scratch directory with a base directory and a file outside of it
+    fn setup(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("stamd-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let basedir = dir.join("base");
+        fs::create_dir_all(basedir.join("sub")).unwrap();
+        fs::write(basedir.join("doc.txt"), "Hello world").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        (dir, basedir)
+    }
+
+    fn store_with_resource(basedir: &Path, include: &str) -> Result<AnnotationStore, ApiError> {
+        let filename = basedir.join("t.store.stam.json");
+        fs::write(
+            &filename,
+            format!(
+                r#"{{"@type":"AnnotationStore","@id":"t","resources":[{{"@type":"TextResource","@id":"doc","@include":"{}"}}]}}"#,
+                include
+            ),
+        )
+        .unwrap();
+        check_store_file(basedir, &filename)?;
+        let store = AnnotationStore::from_file(filename.to_str().unwrap(), Config::default())?;
+        check_files(basedir, &store)?;
+        Ok(store)
+    }
+
+    #[test]
+    fn path_within_basedir() {
+        let (dir, basedir) = setup("within");
+        assert!(check_path(&basedir, &basedir.join("doc.txt")).is_ok());
+        assert!(check_path(&basedir, &basedir.join("sub/../doc.txt")).is_ok());
+        //files that do not exist yet, such as stand-off files written on save
+        assert!(check_path(&basedir, &basedir.join("sub/new/new.txt")).is_ok());
+        assert!(store_with_resource(&basedir, "doc.txt").is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn absolute_path() {
+        let (dir, basedir) = setup("absolute");
+        let secret = dir.join("secret.txt");
+        assert!(check_path(&basedir, &secret).is_err());
+        assert!(check_path(&basedir, Path::new("/etc/passwd")).is_err());
+        assert!(store_with_resource(&basedir, secret.to_str().unwrap()).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parent_directory() {
+        let (dir, basedir) = setup("parent");
+        assert!(check_path(&basedir, &basedir.join("../secret.txt")).is_err());
+        assert!(check_path(&basedir, &basedir.join("sub/../../secret.txt")).is_err());
+        assert!(check_path(&basedir, &basedir.join("sub/new/../../../new.txt")).is_err());
+        assert!(store_with_resource(&basedir, "../secret.txt").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symbolic_link() {
+        let (dir, basedir) = setup("symlink");
+        symlink(dir.join("secret.txt"), basedir.join("link.txt")).unwrap();
+        symlink(&dir, basedir.join("linkdir")).unwrap();
+        symlink(basedir.join("doc.txt"), basedir.join("sub/doc.txt")).unwrap();
+        assert!(check_path(&basedir, &basedir.join("link.txt")).is_err());
+        assert!(check_path(&basedir, &basedir.join("linkdir/secret.txt")).is_err());
+        assert!(check_path(&basedir, &basedir.join("linkdir/new.txt")).is_err());
+        assert!(check_path(&basedir, &basedir.join("sub/doc.txt")).is_ok());
+        assert!(store_with_resource(&basedir, "link.txt").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn substore() {
+        let (dir, basedir) = setup("substore");
+        let load = |include: &str| {
+            //nested includes are relative to the substore that includes them
+            fs::write(
+                basedir.join("sub/nested.store.stam.json"),
+                format!(
+                    r#"{{"@type":"AnnotationStore","@id":"nested","@include":"{}"}}"#,
+                    include
+                ),
+            )
+            .unwrap();
+            let filename = basedir.join("t.store.stam.json");
+            fs::write(
+                &filename,
+                r#"{"@type":"AnnotationStore","@id":"t","@include":"sub/nested.store.stam.json"}"#,
+            )
+            .unwrap();
+            check_store_file(&basedir, &filename)?;
+            let store =
+                AnnotationStore::from_file(filename.to_str().unwrap(), Config::default()).unwrap();
+            check_files(&basedir, &store)
+        };
+        let store = r#"{"@type":"AnnotationStore","@id":"other"}"#;
+        fs::write(basedir.join("sub/inner.store.stam.json"), store).unwrap();
+        fs::write(dir.join("other.store.stam.json"), store).unwrap();
+        assert!(load("inner.store.stam.json").is_ok());
+        assert!(load("../../other.store.stam.json").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn not_a_file() {
+        let (dir, basedir) = setup("notafile");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(basedir.join("fifo.txt"))
+            .status()
+            .unwrap()
+            .success());
+        //these must be refused before STAM reads them, it would block on the FIFO
+        assert!(store_with_resource(&basedir, "fifo.txt").is_err());
+        assert!(store_with_resource(&basedir, "sub").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore_backup() {
+        let (dir, basedir) = setup("restore");
+        store_with_resource(&basedir, "doc.txt").unwrap();
+        let pool = pool(&basedir);
+        for (backup, include) in [(1, "doc.txt"), (2, "../secret.txt")] {
+            fs::write(
+                basedir.join(format!("t.{}.bak", backup)),
+                format!(
+                    r#"{{"@type":"AnnotationStore","@id":"t","resources":[{{"@type":"TextResource","@id":"doc","@include":"{}"}}]}}"#,
+                    include
+                ),
+            )
+            .unwrap();
+        }
+        assert!(pool.restore_backup("t", 1, None).is_ok());
+        assert!(pool.restore_backup("t", 2, None).is_err());
+        drop(pool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn pool(basedir: &Path) -> StorePool {
+        StorePool::new(
+            basedir,
+            "http://localhost/",
+            "store.stam.json",
+            false,
+            600,
+            None,
+            0,
+            0,
+            false,
+            3,
+            false,
+            WebAnnoConfig::default(),
+            Config::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rename_changed_on_disk() {
+        let (dir, basedir) = setup("rename");
+        store_with_resource(&basedir, "doc.txt").unwrap();
+        let pool = pool(&basedir);
+        pool.load("t").unwrap();
+        //someone else saves a new version
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(
+            basedir.join("t.store.stam.json"),
+            r#"{"@type":"AnnotationStore","@id":"t"}"#,
+        )
+        .unwrap();
+        assert!(matches!(pool.rename("t", "u"), Err(ApiError::Conflict(_))));
+        assert!(basedir.join("t.store.stam.json").exists());
+        assert!(!basedir.join("u.store.stam.json").exists());
+        drop(pool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unlogged_change() {
+        let (dir, basedir) = setup("unlogged");
+        store_with_resource(&basedir, "doc.txt").unwrap();
+        let pool = pool(&basedir);
+        pool.load("t").unwrap();
+        //the write-ahead log can not be written
+        fs::create_dir(basedir.join("t.wal")).unwrap();
+        let result = pool.map_mut_recorded("t", None, |store| {
+            store.add_resource(TextResourceBuilder::new().with_id("new").with_text("new"))?;
+            Ok((
+                (),
+                Change::Resource {
+                    id: "new".to_string(),
+                },
+            ))
+        });
+        assert!(result.is_err());
+        assert!(pool
+            .map("t", |store| Ok(store.resource("new").is_none()))
+            .unwrap());
+        fs::remove_dir(basedir.join("t.wal")).unwrap();
+        drop(pool);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_saves() {
+        let (dir, basedir) = setup("saves");
+        store_with_resource(&basedir, "doc.txt").unwrap();
+        let pool = pool(&basedir);
+        pool.load("t").unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let savers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        for _ in 0..10 {
+                            pool.map_mut("t", |_| Ok(())).unwrap();
+                            pool.save("t").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            //our own saves are never mistaken for changes on disk
+            scope.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    assert!(pool.check_changes().unwrap().is_empty());
+                }
+            });
+            for saver in savers {
+                saver.join().unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        assert!(!pool.changed("t").unwrap());
+        assert!(AnnotationStore::from_file(
+            basedir.join("t.store.stam.json").to_str().unwrap(),
+            Config::default()
+        )
+        .is_ok());
+        //no temporary files are left behind
+        for entry in fs::read_dir(&basedir).unwrap() {
+            let filename = entry.unwrap().file_name();
+            assert!(!filename.to_string_lossy().ends_with(".tmp"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
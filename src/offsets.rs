@@ -0,0 +1,417 @@
+use crate::common::ApiError;
+use serde::Serialize;
+use serde_json::value::Value;
+use stam::{Offset, ResultTextSelection, Text};
+
+/// The unit in which offsets on a text resource are expressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OffsetUnit {
+    /// Unicode points, this is what STAM uses natively
+    Unicode,
+    /// UTF-8 bytes
+    Bytes,
+    /// UTF-16 code units (as used by JavaScript)
+    Utf16,
+    /// Line and column (`line:column`), both 1-indexed, the column is in unicode points
+    LineColumn,
+}
+
+impl TryFrom<&str> for OffsetUnit {
+    type Error = ApiError;
+
+    fn try_from(unit: &str) -> Result<Self, ApiError> {
+        match unit {
+            "unicode" => Ok(Self::Unicode),
+            "bytes" => Ok(Self::Bytes),
+            "utf16" => Ok(Self::Utf16),
+            "line" => Ok(Self::LineColumn),
+            _ => Err(ApiError::InvalidArgument(
+                "Invalid unit (must be one of: unicode, bytes, utf16, line)",
+            )),
+        }
+    }
+}
+
+impl OffsetUnit {
+    /// Resolves a begin and end position expressed in this unit to an [`Offset`] in unicode points.
+    /// Only unicode points support end-aligned (negative) cursors.
+    pub fn to_offset(self, text: &str, begin: &str, end: &str) -> Result<Offset, ApiError> {
+        match self {
+            Self::Unicode => Ok(Offset::new(begin.try_into()?, end.try_into()?)),
+            _ => Ok(Offset::simple(
+                self.to_charpos(text, begin)?,
+                self.to_charpos(text, end)?,
+            )),
+        }
+    }
+
+    /// Converts a position in this unit to a position in unicode points
+    fn to_charpos(self, text: &str, position: &str) -> Result<usize, ApiError> {
+        match self {
+            Self::Unicode => position
+                .parse()
+                .map_err(|_| ApiError::InvalidArgument("Invalid offset (expected an integer)")),
+            Self::Bytes => {
+                let bytepos: usize = position.parse().map_err(|_| {
+                    ApiError::InvalidArgument("Invalid offset (expected an integer)")
+                })?;
+                if bytepos > text.len() || !text.is_char_boundary(bytepos) {
+                    return Err(ApiError::InvalidArgument(
+                        "Byte offset is out of bounds or not on a character boundary",
+                    ));
+                }
+                Ok(text[..bytepos].chars().count())
+            }
+            Self::Utf16 => {
+                let utf16pos: usize = position.parse().map_err(|_| {
+                    ApiError::InvalidArgument("Invalid offset (expected an integer)")
+                })?;
+                let mut count = 0;
+                for (charpos, c) in text.chars().enumerate() {
+                    if count == utf16pos {
+                        return Ok(charpos);
+                    } else if count > utf16pos {
+                        break;
+                    }
+                    count += c.len_utf16();
+                }
+                if count == utf16pos {
+                    Ok(text.chars().count())
+                } else {
+                    Err(ApiError::InvalidArgument(
+                        "UTF-16 offset is out of bounds or not on a character boundary",
+                    ))
+                }
+            }
+            Self::LineColumn => {
+                let (line, column) = position.split_once(':').ok_or(ApiError::InvalidArgument(
+                    "Invalid position (expected line:column)",
+                ))?;
+                let line: usize = line
+                    .parse()
+                    .map_err(|_| ApiError::InvalidArgument("Invalid line number"))?;
+                let column: usize = column
+                    .parse()
+                    .map_err(|_| ApiError::InvalidArgument("Invalid column number"))?;
+                if line == 0 || column == 0 {
+                    return Err(ApiError::InvalidArgument(
+                        "Line and column numbers are 1-indexed",
+                    ));
+                }
+                let mut linebegin = 0;
+                for (i, linetext) in text.split('\n').enumerate() {
+                    let linelength = linetext.chars().count();
+                    if i + 1 == line {
+                        if column - 1 > linelength {
+                            return Err(ApiError::InvalidArgument("Column is out of bounds"));
+                        }
+                        return Ok(linebegin + column - 1);
+                    }
+                    linebegin += linelength + 1; //+1 for the newline
+                }
+                Err(ApiError::InvalidArgument("Line is out of bounds"))
+            }
+        }
+    }
+}
+
+/// A single position in a text, expressed in all supported units
+#[derive(Serialize, Debug)]
+pub struct Position {
+    unicode: usize,
+    bytes: usize,
+    utf16: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Position {
+    /// Computes all equivalents of a position given in unicode points
+    pub fn new(text: &str, charpos: usize) -> Self {
+        let mut position = Self {
+            unicode: charpos,
+            bytes: 0,
+            utf16: 0,
+            line: 1,
+            column: 1,
+        };
+        for c in text.chars().take(charpos) {
+            position.bytes += c.len_utf8();
+            position.utf16 += c.len_utf16();
+            if c == '\n' {
+                position.line += 1;
+                position.column = 1;
+            } else {
+                position.column += 1;
+            }
+        }
+        position
+    }
+}
+
+/// Serializes a text selection to STAM JSON, with an extra `offsets` key that expresses its begin and end in all supported units
+pub fn textselection_to_json_value(textselection: &ResultTextSelection) -> Result<Value, ApiError> {
+    let mut value = textselection.to_json_value()?;
+    if let Value::Object(map) = &mut value {
+        let text = textselection.resource().text();
+        map.insert(
+            "offsets".to_string(),
+            serde_json::json!({
+                "begin": Position::new(text, textselection.begin()),
+                "end": Position::new(text, textselection.end()),
+            }),
+        );
+    }
+    Ok(value)
+}
+
+/// Resolves an RFC 5147 fragment identifier (`char=` or `line=` scheme, with or without leading `#`) to an [`Offset`] in unicode points.
+/// Positions beyond the end of the text refer to the end of the text, integrity checks (`;length=`, `;md5=`) are ignored.
+pub fn rfc5147_to_offset(text: &str, fragment: &str) -> Result<Offset, ApiError> {
+    let fragment = fragment.trim_start_matches('#');
+    let fragment = fragment.split(';').next().unwrap_or(fragment);
+    let (scheme, range) = fragment.split_once('=').ok_or(ApiError::InvalidArgument(
+        "Invalid fragment identifier (expected char= or line=)",
+    ))?;
+    let (begin, end) = match range.split_once(',') {
+        Some((begin, end)) => (begin, end),
+        None => (range, range),
+    };
+    let begin: Option<usize> =
+        if begin.is_empty() {
+            None
+        } else {
+            Some(begin.parse().map_err(|_| {
+                ApiError::InvalidArgument("Invalid position in fragment identifier")
+            })?)
+        };
+    let end: Option<usize> =
+        if end.is_empty() {
+            None
+        } else {
+            Some(end.parse().map_err(|_| {
+                ApiError::InvalidArgument("Invalid position in fragment identifier")
+            })?)
+        };
+    let textlen = text.chars().count();
+    let (begin, end) = match scheme {
+        "char" => (
+            begin.unwrap_or(0).min(textlen),
+            end.unwrap_or(textlen).min(textlen),
+        ),
+        "line" => (
+            begin.map(|line| line_to_charpos(text, line)).unwrap_or(0),
+            end.map(|line| line_to_charpos(text, line))
+                .unwrap_or(textlen),
+        ),
+        _ => {
+            return Err(ApiError::InvalidArgument(
+                "Unsupported fragment identifier scheme (expected char= or line=)",
+            ))
+        }
+    };
+    if begin > end {
+        return Err(ApiError::InvalidArgument(
+            "Begin position in fragment identifier may not exceed end position",
+        ));
+    }
+    Ok(Offset::simple(begin, end))
+}
+
+/// Converts an RFC 5147 line position (the position before the given 0-indexed line) to unicode points
+fn line_to_charpos(text: &str, line: usize) -> usize {
+    if line == 0 {
+        return 0;
+    }
+    let mut newlines = 0;
+    for (charpos, c) in text.chars().enumerate() {
+        if c == '\n' {
+            newlines += 1;
+            if newlines == line {
+                return charpos + 1;
+            }
+        }
+    }
+    text.chars().count()
+}
+
+/// Resolves a W3C TextQuoteSelector (`exact` with optional `prefix` and `suffix`) to the [`Offset`] (in unicode points) of the first match
+pub fn textquote_to_offset(
+    text: &str,
+    exact: &str,
+    prefix: Option<&str>,
+    suffix: Option<&str>,
+) -> Result<Offset, ApiError> {
+    if exact.is_empty() {
+        return Err(ApiError::InvalidArgument("Exact text may not be empty"));
+    }
+    //keep track of the character position of the last match, so we don't need to count from the start each time
+    let mut bytepos = 0;
+    let mut charpos = 0;
+    while let Some(found) = text[bytepos..].find(exact) {
+        let matchbegin = bytepos + found;
+        charpos += text[bytepos..matchbegin].chars().count();
+        let matchend = matchbegin + exact.len();
+        if prefix.is_none_or(|prefix| text[..matchbegin].ends_with(prefix))
+            && suffix.is_none_or(|suffix| text[matchend..].starts_with(suffix))
+        {
+            return Ok(Offset::simple(charpos, charpos + exact.chars().count()));
+        }
+        //matches may overlap, so continue searching from the next character
+        let firstchar = text[matchbegin..]
+            .chars()
+            .next()
+            .expect("match can't be empty");
+        bytepos = matchbegin + firstchar.len_utf8();
+        charpos += 1;
+    }
+    Err(ApiError::NotFound("No text matches the quote selector"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Contains a two-byte character, a newline and a character outside the BMP (two UTF-16 code units)
+    const TEXT: &str = "héllo\nwörld 𝄞!";
+
+    #[test]
+    fn charpos_from_units() {
+        for (unit, position, expected) in [
+            (OffsetUnit::Unicode, "3", Some(3)),
+            (OffsetUnit::Unicode, "x", None),
+            (OffsetUnit::Bytes, "0", Some(0)),
+            (OffsetUnit::Bytes, "3", Some(2)),
+            (OffsetUnit::Bytes, "8", Some(7)),
+            (OffsetUnit::Bytes, "14", Some(12)),
+            (OffsetUnit::Bytes, "18", Some(13)),
+            (OffsetUnit::Bytes, "19", Some(14)),
+            (OffsetUnit::Bytes, "2", None),
+            (OffsetUnit::Bytes, "15", None),
+            (OffsetUnit::Bytes, "20", None),
+            (OffsetUnit::Utf16, "0", Some(0)),
+            (OffsetUnit::Utf16, "12", Some(12)),
+            (OffsetUnit::Utf16, "14", Some(13)),
+            (OffsetUnit::Utf16, "15", Some(14)),
+            (OffsetUnit::Utf16, "13", None),
+            (OffsetUnit::Utf16, "16", None),
+            (OffsetUnit::LineColumn, "1:1", Some(0)),
+            (OffsetUnit::LineColumn, "1:6", Some(5)),
+            (OffsetUnit::LineColumn, "2:1", Some(6)),
+            (OffsetUnit::LineColumn, "2:3", Some(8)),
+            (OffsetUnit::LineColumn, "2:9", Some(14)),
+            (OffsetUnit::LineColumn, "1:7", None),
+            (OffsetUnit::LineColumn, "3:1", None),
+            (OffsetUnit::LineColumn, "0:1", None),
+            (OffsetUnit::LineColumn, "1:0", None),
+            (OffsetUnit::LineColumn, "1", None),
+        ] {
+            assert_eq!(
+                unit.to_charpos(TEXT, position).ok(),
+                expected,
+                "{:?} {}",
+                unit,
+                position
+            );
+        }
+    }
+
+    #[test]
+    fn offset_from_units() {
+        assert_eq!(
+            OffsetUnit::Bytes.to_offset(TEXT, "7", "13").unwrap(),
+            Offset::simple(6, 11)
+        );
+        assert_eq!(
+            OffsetUnit::LineColumn
+                .to_offset(TEXT, "2:1", "2:6")
+                .unwrap(),
+            Offset::simple(6, 11)
+        );
+        //only unicode points support end-aligned cursors
+        assert_eq!(
+            OffsetUnit::Unicode.to_offset(TEXT, "6", "-3").unwrap(),
+            Offset::new(stam::Cursor::BeginAligned(6), stam::Cursor::EndAligned(-3))
+        );
+        assert!(OffsetUnit::Utf16.to_offset(TEXT, "6", "-3").is_err());
+    }
+
+    #[test]
+    fn position_in_all_units() {
+        for (charpos, bytes, utf16, line, column) in [
+            (0, 0, 0, 1, 1),
+            (2, 3, 2, 1, 3),
+            (6, 7, 6, 2, 1),
+            (13, 18, 14, 2, 8),
+            (14, 19, 15, 2, 9),
+        ] {
+            let position = Position::new(TEXT, charpos);
+            assert_eq!(
+                (
+                    position.unicode,
+                    position.bytes,
+                    position.utf16,
+                    position.line,
+                    position.column
+                ),
+                (charpos, bytes, utf16, line, column),
+                "position {}",
+                charpos
+            );
+        }
+    }
+
+    #[test]
+    fn rfc5147() {
+        let text = "one\ntwo\nthree";
+        for (fragment, expected) in [
+            ("char=0,3", Some((0, 3))),
+            ("#char=4", Some((4, 4))),
+            ("char=,3", Some((0, 3))),
+            ("char=10,", Some((10, 13))),
+            ("char=5,99", Some((5, 13))),
+            ("char=0,3;length=13", Some((0, 3))),
+            ("line=1,2", Some((4, 8))),
+            ("line=1", Some((4, 4))),
+            ("line=,1", Some((0, 4))),
+            ("line=2,", Some((8, 13))),
+            ("line=5,9", Some((13, 13))),
+            ("char=3,1", None),
+            ("char=a", None),
+            ("page=1", None),
+            ("char", None),
+        ] {
+            assert_eq!(
+                rfc5147_to_offset(text, fragment).ok(),
+                expected.map(|(begin, end)| Offset::simple(begin, end)),
+                "{}",
+                fragment
+            );
+        }
+    }
+
+    #[test]
+    fn textquote() {
+        for (text, exact, prefix, suffix, expected) in [
+            ("abab ab cab", "ab", None, None, Some((0, 2))),
+            ("abab ab cab", "ab", Some("c"), None, Some((9, 11))),
+            ("abab ab cab", "ab", None, Some(" c"), Some((5, 7))),
+            ("abab ab cab", "ab", Some("ab"), Some(" "), Some((2, 4))),
+            //overlapping matches
+            ("aaab", "aa", None, Some("b"), Some((1, 3))),
+            ("ééé", "é", Some("éé"), None, Some((2, 3))),
+            ("abab ab cab", "x", None, None, None),
+            ("abab ab cab", "ab", Some("x"), None, None),
+            ("abab ab cab", "", None, None, None),
+        ] {
+            assert_eq!(
+                textquote_to_offset(text, exact, prefix, suffix).ok(),
+                expected.map(|(begin, end)| Offset::simple(begin, end)),
+                "{} {:?} {:?}",
+                exact,
+                prefix,
+                suffix
+            );
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::common::{parse_query, quote_stamql, ApiError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use utoipa::ToSchema;
+
+/// Request parameters that configure the output of a query, these can not be used as query parameters
+const RESERVED_PARAMETERS: &[&str] = &["use", "columns", "delimiter", "vardelimiter", "save"];
+
+/// Value used for parameters without default when validating a query, it is valid both as a number and as a string
+const VALIDATION_VALUE: &str = "0";
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+/// A named query, saved alongside a store. The query may contain parameters (`$name`) in places where STAMQL expects a value,
+/// these are substituted when the query is run.
+pub struct SavedQuery {
+    /// The query in STAMQL, with parameters
+    pub query: String,
+
+    /// A human readable description of the query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Default values for parameters, parameters without a default are required
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub parameters: BTreeMap<String, String>,
+}
+
+impl SavedQuery {
+    /// Checks that the query and its parameters are valid
+    pub fn validate(&self) -> Result<(), ApiError> {
+        for name in self.placeholders() {
+            if RESERVED_PARAMETERS.contains(&name) {
+                return Err(ApiError::InvalidArgument(
+                    "Parameter names use, columns, delimiter, vardelimiter and save are reserved",
+                ));
+            }
+        }
+        for name in self.parameters.keys() {
+            if !self.placeholders().contains(&name.as_str()) {
+                return Err(ApiError::InvalidArgument(
+                    "A default is given for a parameter that does not occur in the query",
+                ));
+            }
+        }
+        let mut values: HashMap<String, String> = HashMap::new();
+        for name in self.placeholders() {
+            if !self.parameters.contains_key(name) {
+                values.insert(name.to_string(), VALIDATION_VALUE.to_string());
+            }
+        }
+        parse_query(&self.instantiate(&values)?)?;
+        Ok(())
+    }
+
+    /// Returns the names of all parameters in the query, in order of first occurrence
+    pub fn placeholders(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        for (_, name) in tokenize(&self.query) {
+            if let Some(name) = name {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Substitutes all parameters with the passed values (or their defaults). Numbers are inserted as-is,
+    /// all other values are quoted as STAMQL strings, so they can never alter the structure of the query.
+    /// Values that can't be quoted safely are rejected, as are unknown parameters.
+    pub fn instantiate(&self, values: &HashMap<String, String>) -> Result<String, ApiError> {
+        let placeholders = self.placeholders();
+        for name in values.keys() {
+            if !RESERVED_PARAMETERS.contains(&name.as_str())
+                && !placeholders.contains(&name.as_str())
+            {
+                return Err(ApiError::InvalidArgument(
+                    "Unknown parameter for this query",
+                ));
+            }
+        }
+        let mut querystring = String::with_capacity(self.query.len());
+        for (text, name) in tokenize(&self.query) {
+            querystring += text;
+            if let Some(name) = name {
+                let value = values
+                    .get(name)
+                    .or_else(|| self.parameters.get(name))
+                    .ok_or(ApiError::InvalidArgument(
+                        "A required parameter for this query is missing",
+                    ))?;
+                if is_number(value) {
+                    querystring += value;
+                } else {
+                    querystring += &quote_stamql(value)?;
+                }
+            }
+        }
+        Ok(querystring)
+    }
+}
+
+/// Splits a query into pieces of literal text, each followed by an optional parameter name.
+/// Parameters (`$name`) are only recognised outside string literals. Like the STAMQL parser, a quote preceded by a backslash
+/// does not end a string literal.
+fn tokenize(query: &str) -> Vec<(&str, Option<&str>)> {
+    let mut tokens = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut begin = 0;
+    let mut chars = query.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let prev_escaped = escaped;
+        escaped = c == '\\';
+        if c == '"' && !prev_escaped {
+            quoted = !quoted;
+        } else if c == '$' && !quoted {
+            let mut end = i + 1;
+            while let Some((j, c)) = chars.peek() {
+                if c.is_alphanumeric() || *c == '_' {
+                    end = j + c.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            if end > i + 1 {
+                tokens.push((&query[begin..i], Some(&query[i + 1..end])));
+                begin = end;
+            }
+        }
+    }
+    tokens.push((&query[begin..], None));
+    tokens
+}
+
+fn is_number(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, "0"));
+    !integer.is_empty()
+        && !fraction.is_empty()
+        && integer.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Checks that a name for a saved query only consists of alphanumeric characters, dashes and underscores
+pub fn check_name(name: &str) -> Result<(), ApiError> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidArgument(
+            "Query names may only contain alphanumeric characters, dashes and underscores",
+        ))
+    }
+}
+
+/// Reads all saved queries from file, a missing file means there are no saved queries
+pub fn read(filename: &Path) -> Result<BTreeMap<String, SavedQuery>, ApiError> {
+    if !filename.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let data = std::fs::read_to_string(filename)
+        .map_err(|_| ApiError::InternalError("Unable to read saved queries"))?;
+    serde_json::from_str(&data).map_err(|_| ApiError::InternalError("Invalid saved queries file"))
+}
+
+/// Writes all saved queries to file, the file is removed if there are none left
+pub fn write(filename: &Path, queries: &BTreeMap<String, SavedQuery>) -> Result<(), ApiError> {
+    if queries.is_empty() {
+        if filename.exists() {
+            std::fs::remove_file(filename)
+                .map_err(|_| ApiError::InternalError("Unable to remove saved queries"))?;
+        }
+        return Ok(());
+    }
+    let data = serde_json::to_string_pretty(queries)
+        .map_err(|_| ApiError::InternalError("Unable to serialize saved queries"))?;
+    std::fs::write(filename, data)
+        .map_err(|_| ApiError::InternalError("Unable to write saved queries"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn savedquery(query: &str, defaults: &[(&str, &str)]) -> SavedQuery {
+        SavedQuery {
+            query: query.to_string(),
+            description: None,
+            parameters: defaults
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        for (query, expected) in [
+            (
+                "SELECT ANNOTATION ?a WHERE ID $id;",
+                vec![("SELECT ANNOTATION ?a WHERE ID ", Some("id")), (";", None)],
+            ),
+            (
+                r#"ID "$id" $x"#,
+                vec![(r#"ID "$id" "#, Some("x")), ("", None)],
+            ),
+            //an escaped quote does not end the string literal
+            (
+                r#"ID "a\"$id" $x;"#,
+                vec![(r#"ID "a\"$id" "#, Some("x")), (";", None)],
+            ),
+            (r#"ID "a\\" $x"#, vec![(r#"ID "a\\" $x"#, None)]),
+            (
+                "OFFSET $begin $end_1;",
+                vec![
+                    ("OFFSET ", Some("begin")),
+                    (" ", Some("end_1")),
+                    (";", None),
+                ],
+            ),
+            ("$ $é", vec![("$ ", Some("é")), ("", None)]),
+        ] {
+            assert_eq!(tokenize(query), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        for (value, expected) in [
+            ("1", true),
+            ("-1", true),
+            ("1.5", true),
+            ("-0.25", true),
+            ("", false),
+            ("-", false),
+            ("1.", false),
+            (".5", false),
+            ("1e5", false),
+            ("0x1", false),
+            ("1.2.3", false),
+            (" 1", false),
+        ] {
+            assert_eq!(is_number(value), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn instantiate() {
+        let query = savedquery(
+            r#"SELECT ANNOTATION ?a WHERE DATA "set" $key = $value;"#,
+            &[("key", "k")],
+        );
+        for (values, expected) in [
+            (
+                vec![("value", "5")],
+                Some(r#"SELECT ANNOTATION ?a WHERE DATA "set" "k" = 5;"#),
+            ),
+            (
+                vec![("value", "x y"), ("key", "k2")],
+                Some(r#"SELECT ANNOTATION ?a WHERE DATA "set" "k2" = "x y";"#),
+            ),
+            //reserved parameters are not substituted, but are allowed
+            (
+                vec![("value", "-1.5"), ("use", "a")],
+                Some(r#"SELECT ANNOTATION ?a WHERE DATA "set" "k" = -1.5;"#),
+            ),
+            (vec![], None),
+            (vec![("value", "5"), ("other", "1")], None),
+            (vec![("value", r#"a" OR "b"#)], None),
+            (vec![("value", r"a\")], None),
+        ] {
+            let values: HashMap<String, String> = values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            assert_eq!(
+                query.instantiate(&values).ok().as_deref(),
+                expected,
+                "{:?}",
+                values
+            );
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(savedquery("SELECT ANNOTATION ?a WHERE ID $id;", &[])
+            .validate()
+            .is_ok());
+        assert!(savedquery("SELECT ANNOTATION ?a WHERE ID $use;", &[])
+            .validate()
+            .is_err());
+        assert!(
+            savedquery("SELECT ANNOTATION ?a WHERE ID $id;", &[("x", "1")])
+                .validate()
+                .is_err()
+        );
+        assert!(savedquery("SELECT ANNOTATION ?a WHERE FOO $id;", &[])
+            .validate()
+            .is_err());
+        //queries the parser would panic on
+        for query in ["SELECT", "SELECT ANNOTATION ?a; { ADDé }"] {
+            assert!(savedquery(query, &[]).validate().is_err(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use crate::budget::QueryBudget;
+use crate::common::ApiError;
+use stam::{QueryIter, QueryResultItem, QueryResultItems, Text};
+
+/// Delimiter between multiple values in a single cell, e.g. for an annotation that targets multiple text selections
+const VALUE_DELIMITER: &str = "|";
+
+/// What information to output for a result item in a column
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Projection {
+    /// The public identifier, or the text for text selections
+    Default,
+    /// The public identifier
+    Id,
+    /// The text
+    Text,
+    /// The begin offset (unicode points)
+    Begin,
+    /// The end offset (unicode points)
+    End,
+    /// The identifier of the resource
+    Resource,
+    /// The type of the item (Annotation, TextSelection, etc..)
+    Type,
+    /// The value of the annotation data with the specified set and key
+    Data { set: String, key: String },
+}
+
+/// A column in tabular output, corresponds to a variable in the query
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Column {
+    variable: String,
+    projection: Projection,
+}
+
+impl Column {
+    fn header(&self) -> String {
+        match &self.projection {
+            Projection::Default => self.variable.clone(),
+            Projection::Id => format!("{}:id", self.variable),
+            Projection::Text => format!("{}:text", self.variable),
+            Projection::Begin => format!("{}:begin", self.variable),
+            Projection::End => format!("{}:end", self.variable),
+            Projection::Resource => format!("{}:resource", self.variable),
+            Projection::Type => format!("{}:type", self.variable),
+            Projection::Data { set, key } => format!("{}:data({},{})", self.variable, set, key),
+        }
+    }
+}
+
+/// Parses a column specification. Columns are separated by commas, each column takes the form
+/// `variable` or `variable:projection` (a leading `?` on the variable is optional), where projection is one of
+/// `id`, `text`, `begin`, `end`, `resource`, `type`, or `data("set","key")`.
+pub fn parse_columns(spec: &str) -> Result<Vec<Column>, ApiError> {
+    let mut columns = Vec::new();
+    //split on commas that are not within quotes or parentheses
+    let mut quoted = false;
+    let mut depth = 0;
+    let mut begin = 0;
+    for (i, c) in spec
+        .char_indices()
+        .chain(std::iter::once((spec.len(), ',')))
+    {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == '(' && !quoted {
+            depth += 1;
+        } else if c == ')' && !quoted && depth > 0 {
+            depth -= 1;
+        } else if c == ',' && !quoted && depth == 0 {
+            let column = spec[begin..i].trim();
+            if !column.is_empty() {
+                columns.push(parse_column(column)?);
+            }
+            begin = i + 1;
+        }
+    }
+    if quoted {
+        return Err(ApiError::InvalidArgument("Unterminated quote in columns"));
+    }
+    Ok(columns)
+}
+
+fn parse_column(column: &str) -> Result<Column, ApiError> {
+    let column = column.strip_prefix('?').unwrap_or(column);
+    let (variable, projection) = match column.split_once(':') {
+        Some((variable, projection)) => (variable, projection.trim()),
+        None => (column, ""),
+    };
+    let projection = match projection {
+        "" => Projection::Default,
+        "id" => Projection::Id,
+        "text" => Projection::Text,
+        "begin" => Projection::Begin,
+        "end" => Projection::End,
+        "resource" => Projection::Resource,
+        "type" => Projection::Type,
+        projection => {
+            let args = projection
+                .strip_prefix("data(")
+                .and_then(|s| s.strip_suffix(')'))
+                .ok_or(ApiError::InvalidArgument(
+                    "Invalid column projection (must be one of: id, text, begin, end, resource, type, data(\"set\",\"key\"))",
+                ))?;
+            let args: Vec<&str> = args
+                .split("\",")
+                .map(|s| s.trim().trim_matches('"'))
+                .collect();
+            if args.len() != 2 {
+                return Err(ApiError::InvalidArgument(
+                    "Invalid data projection (expected data(\"set\",\"key\"))",
+                ));
+            }
+            Projection::Data {
+                set: args[0].to_string(),
+                key: args[1].to_string(),
+            }
+        }
+    };
+    Ok(Column {
+        variable: variable.trim().to_string(),
+        projection,
+    })
+}
+
+/// Writes query results as a table, with a header row, one row per result and one column per variable (or the specified columns).
+/// The delimiter is either a tab (TSV) or a comma (CSV).
+pub fn write_table(
+    queryiter: QueryIter,
+    columns: Option<Vec<Column>>,
+    delimiter: char,
+    budget: &QueryBudget,
+) -> Result<String, ApiError> {
+    let mut out = String::new();
+    let mut columns = columns;
+    for resultitems in queryiter {
+        budget.check()?;
+        if columns.is_none() {
+            //default: all named variables with default projection
+            columns = Some(
+                resultitems
+                    .names()
+                    .flatten()
+                    .map(|name| Column {
+                        variable: name.to_string(),
+                        projection: Projection::Default,
+                    })
+                    .collect(),
+            );
+        }
+        if out.is_empty() {
+            write_header(&mut out, columns.iter().flatten(), delimiter);
+        }
+        let mut row = Vec::new();
+        for column in columns.iter().flatten() {
+            row.push(project(&resultitems, column)?);
+        }
+        write_row(&mut out, row.into_iter(), delimiter);
+    }
+    if out.is_empty() {
+        //no results, we can still output a header if we know the columns
+        write_header(&mut out, columns.iter().flatten(), delimiter);
+    }
+    Ok(out)
+}
+
+fn write_header<'a>(out: &mut String, columns: impl Iterator<Item = &'a Column>, delimiter: char) {
+    write_row(out, columns.map(|column| column.header()), delimiter);
+}
+
+fn write_row(out: &mut String, cells: impl Iterator<Item = String>, delimiter: char) {
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            out.push(delimiter);
+        }
+        if delimiter == ',' {
+            //CSV (RFC 4180): quote cells with special characters, double any quotes
+            if cell.contains([',', '"', '\n', '\r']) {
+                out.push('"');
+                out.push_str(&cell.replace('"', "\"\""));
+                out.push('"');
+            } else {
+                out.push_str(&cell);
+            }
+        } else {
+            //TSV: escape characters that can't be represented
+            out.push_str(
+                &cell
+                    .replace('\\', "\\\\")
+                    .replace('\t', "\\t")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r"),
+            );
+        }
+    }
+    out.push_str(if delimiter == ',' { "\r\n" } else { "\n" });
+}
+
+/// Returns the value of a cell
+fn project(resultitems: &QueryResultItems, column: &Column) -> Result<String, ApiError> {
+    let item = resultitems.get_by_name(&column.variable).map_err(|_| {
+        ApiError::InvalidArgument("Column refers to a variable that is not in the query")
+    })?;
+    let value = match (&column.projection, item) {
+        (Projection::Default, QueryResultItem::TextSelection(textselection)) => {
+            textselection.text().to_string()
+        }
+        (Projection::Default, item) | (Projection::Id, item) => id(item).unwrap_or_default(),
+        (Projection::Text, item) => item
+            .text(Some(VALUE_DELIMITER))
+            .map(|text| text.to_string())
+            .unwrap_or_default(),
+        (Projection::Begin, QueryResultItem::TextSelection(textselection)) => {
+            textselection.begin().to_string()
+        }
+        (Projection::Begin, QueryResultItem::Annotation(annotation)) => annotation
+            .textselections()
+            .map(|textselection| textselection.begin().to_string())
+            .collect::<Vec<_>>()
+            .join(VALUE_DELIMITER),
+        (Projection::End, QueryResultItem::TextSelection(textselection)) => {
+            textselection.end().to_string()
+        }
+        (Projection::End, QueryResultItem::Annotation(annotation)) => annotation
+            .textselections()
+            .map(|textselection| textselection.end().to_string())
+            .collect::<Vec<_>>()
+            .join(VALUE_DELIMITER),
+        (Projection::Resource, QueryResultItem::TextSelection(textselection)) => textselection
+            .resource()
+            .id()
+            .unwrap_or_default()
+            .to_string(),
+        (Projection::Resource, QueryResultItem::Annotation(annotation)) => annotation
+            .resources()
+            .filter_map(|resource| resource.id().map(|s| s.to_string()))
+            .collect::<Vec<_>>()
+            .join(VALUE_DELIMITER),
+        (Projection::Type, item) => item_type(item).to_string(),
+        (Projection::Data { set, key }, QueryResultItem::Annotation(annotation)) => annotation
+            .data()
+            .filter(|data| data.set().id() == Some(set) && data.key().id() == Some(key))
+            .map(|data| data.value().to_string())
+            .collect::<Vec<_>>()
+            .join(VALUE_DELIMITER),
+        (Projection::Data { set, key }, QueryResultItem::AnnotationData(data)) => {
+            if data.set().id() == Some(set) && data.key().id() == Some(key) {
+                data.value().to_string()
+            } else {
+                String::new()
+            }
+        }
+        _ => String::new(),
+    };
+    Ok(value)
+}
+
+fn id(item: &QueryResultItem) -> Option<String> {
+    match item {
+        QueryResultItem::Annotation(annotation) => annotation.id().map(|s| s.to_string()),
+        QueryResultItem::TextResource(resource) => resource.id().map(|s| s.to_string()),
+        QueryResultItem::DataKey(key) => key.id().map(|s| s.to_string()),
+        QueryResultItem::AnnotationData(data) => data.id().map(|s| s.to_string()),
+        QueryResultItem::AnnotationDataSet(dataset) => dataset.id().map(|s| s.to_string()),
+        QueryResultItem::AnnotationSubStore(substore) => substore.id().map(|s| s.to_string()),
+        QueryResultItem::TextSelection(_) | QueryResultItem::None => None,
+    }
+}
+
+fn item_type(item: &QueryResultItem) -> &'static str {
+    match item {
+        QueryResultItem::Annotation(_) => "Annotation",
+        QueryResultItem::TextResource(_) => "TextResource",
+        QueryResultItem::DataKey(_) => "DataKey",
+        QueryResultItem::AnnotationData(_) => "AnnotationData",
+        QueryResultItem::AnnotationDataSet(_) => "AnnotationDataSet",
+        QueryResultItem::AnnotationSubStore(_) => "AnnotationSubStore",
+        QueryResultItem::TextSelection(_) => "TextSelection",
+        QueryResultItem::None => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::parse_query;
+    use stam::{AnnotationStore, Config};
+
+    fn store() -> AnnotationStore {
+        AnnotationStore::from_str(
+            r#"{"@type":"AnnotationStore","@id":"t",
+                "resources":[{"@type":"TextResource","@id":"doc","text":"Hello \"big\" world"}],
+                "annotationsets":[
+                    {"@type":"AnnotationDataSet","@id":"s","keys":[{"@type":"DataKey","@id":"k"}]},
+                    {"@type":"AnnotationDataSet","@id":"t","keys":[{"@type":"DataKey","@id":"k"}]}
+                ],
+                "annotations":[
+                    {"@type":"Annotation","@id":"A1",
+                     "target":{"@type":"TextSelector","resource":"doc","offset":{"@type":"Offset","begin":{"@type":"BeginAlignedCursor","value":0},"end":{"@type":"BeginAlignedCursor","value":5}}},
+                     "data":[{"@type":"AnnotationData","set":"s","key":"k","value":{"@type":"String","value":"a,b"}},
+                             {"@type":"AnnotationData","set":"t","key":"k","value":{"@type":"String","value":"other"}}]},
+                    {"@type":"Annotation","@id":"A2",
+                     "target":{"@type":"TextSelector","resource":"doc","offset":{"@type":"Offset","begin":{"@type":"BeginAlignedCursor","value":6},"end":{"@type":"BeginAlignedCursor","value":11}}},
+                     "data":[{"@type":"AnnotationData","set":"s","key":"k","value":{"@type":"String","value":"tab\there"}}]}
+                ]}"#,
+            Config::default(),
+        )
+        .unwrap()
+    }
+
+    fn table(
+        querystring: &str,
+        columns: Option<&str>,
+        delimiter: char,
+    ) -> Result<String, ApiError> {
+        let store = store();
+        let (query, _) = parse_query(querystring).unwrap();
+        write_table(
+            store.query(query).unwrap(),
+            columns.map(parse_columns).transpose()?,
+            delimiter,
+            &QueryBudget::new(None),
+        )
+    }
+
+    #[test]
+    fn columns() {
+        for (spec, expected) in [
+            ("a", Some(vec![("a", Projection::Default)])),
+            ("?a", Some(vec![("a", Projection::Default)])),
+            (
+                "a:id, ?b:text,,c:begin",
+                Some(vec![
+                    ("a", Projection::Id),
+                    ("b", Projection::Text),
+                    ("c", Projection::Begin),
+                ]),
+            ),
+            (
+                "a:end,a:resource,a:type",
+                Some(vec![
+                    ("a", Projection::End),
+                    ("a", Projection::Resource),
+                    ("a", Projection::Type),
+                ]),
+            ),
+            (
+                r#"a:data("my,set", "key:1"),b"#,
+                Some(vec![
+                    (
+                        "a",
+                        Projection::Data {
+                            set: "my,set".to_string(),
+                            key: "key:1".to_string(),
+                        },
+                    ),
+                    ("b", Projection::Default),
+                ]),
+            ),
+            ("", Some(vec![])),
+            ("a:value", None),
+            (r#"a:data("set")"#, None),
+            (r#"a:data("set","key)"#, None),
+        ] {
+            let expected = expected.map(|columns| {
+                columns
+                    .into_iter()
+                    .map(|(variable, projection)| Column {
+                        variable: variable.to_string(),
+                        projection,
+                    })
+                    .collect::<Vec<_>>()
+            });
+            assert_eq!(parse_columns(spec).ok(), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn tables() {
+        let query = r#"SELECT ANNOTATION ?a WHERE DATA "s" "k";"#;
+        let columns = r#"a:text,a:begin,a:end,a:resource,a:type,a:data("s","k")"#;
+        for (querystring, columns, delimiter, expected) in [
+            (query, None, '\t', "a\nA1\nA2\n"),
+            (query, Some("a:id"), ',', "a:id\r\nA1\r\nA2\r\n"),
+            (
+                query,
+                Some(columns),
+                '\t',
+                "a:text\ta:begin\ta:end\ta:resource\ta:type\ta:data(s,k)\nHello\t0\t5\tdoc\tAnnotation\ta,b\n\"big\"\t6\t11\tdoc\tAnnotation\ttab\\there\n",
+            ),
+            (
+                query,
+                Some(columns),
+                ',',
+                "a:text,a:begin,a:end,a:resource,a:type,\"a:data(s,k)\"\r\nHello,0,5,doc,Annotation,\"a,b\"\r\n\"\"\"big\"\"\",6,11,doc,Annotation,tab\there\r\n",
+            ),
+            //the same key in different sets
+            (
+                query,
+                Some(r#"a:data("s","k"),a:data("t","k")"#),
+                '\t',
+                "a:data(s,k)\ta:data(t,k)\na,b\tother\ntab\\there\t\n",
+            ),
+            //no results, only a header
+            (
+                r#"SELECT ANNOTATION ?a WHERE DATA "s" "k" = "none";"#,
+                Some("a,a:text"),
+                ',',
+                "a,a:text\r\n",
+            ),
+        ] {
+            assert_eq!(
+                table(querystring, columns, delimiter).unwrap(),
+                expected,
+                "{:?} {:?}",
+                columns,
+                delimiter
+            );
+        }
+        assert!(table(query, Some("b"), '\t').is_err());
+    }
+}
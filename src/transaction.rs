@@ -0,0 +1,48 @@
+use crate::budget::QueryBudget;
+use crate::common::{parse_query, ApiError, ApiResponse};
+use crate::history::Change;
+use crate::multistore::StorePool;
+use crate::{query_results, ResultOptions, CONTENT_TYPE_JSON};
+
+/// Executes a list of queries as a single transaction under one write lock on the store.
+/// Either all queries succeed, or the store is rolled back to its state before the transaction.
+/// Returns the JSON results of each query, in the same order.
+pub fn execute(
+    store_id: &str,
+    queries: Vec<String>,
+    user: Option<&str>,
+    storepool: &StorePool,
+    budget: &QueryBudget,
+) -> Result<ApiResponse, ApiError> {
+    //parse everything first, so syntax errors don't even require a rollback
+    let mut parsed = Vec::with_capacity(queries.len());
+    for querystring in queries.iter() {
+        let (query, _) = parse_query(querystring)?;
+        parsed.push(query);
+    }
+    storepool.transaction(store_id, user, |store| {
+        let mut results = Vec::with_capacity(parsed.len());
+        for query in parsed {
+            budget.check()?;
+            let queryiter = store.query_mut(query)?;
+            let options = ResultOptions::new(None, None, None, None)?;
+            results.push(
+                query_results(
+                    queryiter,
+                    CONTENT_TYPE_JSON,
+                    options,
+                    budget,
+                    store_id,
+                    storepool,
+                )?
+                .into_json_value()?,
+            );
+        }
+        Ok((
+            ApiResponse::JsonList(results),
+            Change::Query {
+                queries: queries.clone(),
+            },
+        ))
+    })
+}
@@ -0,0 +1,23 @@
+//! Static assets for the interactive query interface, embedded in the binary
+
+const INDEX_HTML: &str = include_str!("ui/index.html");
+const STAMD_JS: &str = include_str!("ui/stamd.js");
+const STAMD_CSS: &str = include_str!("ui/stamd.css");
+
+/// Returns the content type and content of an embedded asset, by filename
+pub fn asset(name: &str) -> Option<(&'static str, &'static str)> {
+    match name {
+        "stamd.js" => Some(("text/javascript; charset=utf-8", STAMD_JS)),
+        "stamd.css" => Some(("text/css; charset=utf-8", STAMD_CSS)),
+        _ => None,
+    }
+}
+
+/// Renders the main page of the query interface, takes a list of store IDs
+pub fn index(store_ids: &[String]) -> String {
+    //'<' is escaped so a store ID can never close the script element
+    let store_ids = serde_json::to_string(store_ids)
+        .unwrap_or_else(|_| "[]".to_string())
+        .replace('<', "\\u003c");
+    INDEX_HTML.replace("/*STORES*/[]", &store_ids)
+}
@@ -0,0 +1,314 @@
+// Interactive query interface for stamd
+
+const KEYWORDS = new Set(["SELECT", "ADD", "DELETE", "WHERE", "WITH", "TARGET", "OPTIONAL", "UNION",
+    "ANNOTATION", "ANNOTATIONS", "TEXT", "RESOURCE", "RESOURCES", "DATA", "DATASET", "KEY", "KEYS", "VALUE",
+    "SUBSTORE", "TEXTSELECTIONS", "ID", "OFFSET", "RELATION", "AS", "METADATA", "LIMIT", "NOCASE", "REGEX", "REGEXP",
+    "EQUALS", "OVERLAPS", "EMBEDS", "EMBEDDED", "BEFORE", "AFTER", "PRECEDES", "SUCCEEDS", "SAMEBEGIN", "SAMEEND",
+    "ALL", "NONE", "FIRST", "LAST", "COMPOSITE", "MULTI", "DIRECTIONAL", "RECURSIVE", "WHOLE"]);
+
+const DEFAULT_EXAMPLES = [
+    "SELECT RESOURCE ?resource",
+    "SELECT ANNOTATION ?annotation WHERE\n    TEXT \"example\";",
+    "SELECT ANNOTATION ?annotation WHERE\n    DATA \"set\" \"key\" = \"value\";",
+    "SELECT ANNOTATION ?annotation WHERE\n    DATA \"set\" \"key\";\n{\n    SELECT ANNOTATION ?embedded WHERE\n        RELATION ?annotation EMBEDS;\n}",
+];
+
+const HISTORY_SIZE = 50;
+
+// delay (ms) after the last keystroke before the query is validated
+const LINT_DELAY = 300;
+
+const $ = (id) => document.getElementById(id);
+
+function escapeHtml(s) {
+    return s.replace(/&/g, "&amp;").replace(/</g, "&lt;").replace(/>/g, "&gt;").replace(/"/g, "&quot;");
+}
+
+function storeUrl(store) {
+    return "/" + store.split("/").map(encodeURIComponent).join("/");
+}
+
+// Syntax highlighting for STAMQL, renders into the <pre> behind the (transparent) textarea
+function highlight(query) {
+    const pattern = /("(?:[^"\\]|\\.)*"?)|(\?[\w-]+)|(@[\w=-]+)|(-?\d+(?:\.\d+)?)|([A-Za-z_]+)|(!=|<=|>=|=|<|>|\|)|([\s\S])/g;
+    let html = "";
+    let match;
+    while ((match = pattern.exec(query)) !== null) {
+        const [token, string, variable, attribute, number, word, operator] = match;
+        if (string !== undefined) {
+            html += `<span class="string">${escapeHtml(string)}</span>`;
+        } else if (variable !== undefined) {
+            html += `<span class="variable">${escapeHtml(variable)}</span>`;
+        } else if (attribute !== undefined) {
+            html += `<span class="attribute">${escapeHtml(attribute)}</span>`;
+        } else if (number !== undefined) {
+            html += `<span class="number">${number}</span>`;
+        } else if (word !== undefined && KEYWORDS.has(word)) {
+            html += `<span class="keyword">${word}</span>`;
+        } else if (operator !== undefined) {
+            html += `<span class="operator">${escapeHtml(operator)}</span>`;
+        } else {
+            html += escapeHtml(token);
+        }
+    }
+    // a trailing newline is not rendered by a pre, keep the layout in sync with the textarea
+    $("highlight").innerHTML = html + "\n";
+}
+
+// Validates the query as the user types, without running it
+let lintTimer = null;
+function lint() {
+    clearTimeout(lintTimer);
+    lintTimer = setTimeout(async () => {
+        const query = $("query").value;
+        if (!query.trim()) {
+            $("lint").textContent = "";
+            return;
+        }
+        try {
+            const response = await fetch("/_explain?query=" + encodeURIComponent(query), { headers: { "Accept": "application/json" } });
+            const result = await response.json();
+            if (query !== $("query").value) return; // outdated
+            if (result.valid) {
+                $("lint").textContent = "";
+            } else {
+                const position = result.error.position;
+                $("lint").textContent = (position ? `Line ${position.line}, column ${position.column}: ` : "") + result.error.message;
+            }
+        } catch (e) {
+            $("lint").textContent = "";
+        }
+    }, LINT_DELAY);
+}
+
+function currentStore() {
+    return $("store").value;
+}
+
+function loadList(key) {
+    try {
+        return JSON.parse(localStorage.getItem(key)) || [];
+    } catch (e) {
+        return [];
+    }
+}
+
+function saveList(key, list) {
+    localStorage.setItem(key, JSON.stringify(list));
+}
+
+function setQuery(query) {
+    $("query").value = query;
+    highlight(query);
+    lint();
+}
+
+function renderQueryList(element, queries, ondelete) {
+    element.innerHTML = "";
+    queries.forEach((query, i) => {
+        const li = document.createElement("li");
+        li.textContent = query.replace(/\s+/g, " ");
+        li.title = query;
+        li.addEventListener("click", () => setQuery(query));
+        if (ondelete) {
+            const del = document.createElement("span");
+            del.className = "delete";
+            del.textContent = "×";
+            del.title = "Delete";
+            del.addEventListener("click", (e) => {
+                e.stopPropagation();
+                ondelete(i);
+            });
+            li.prepend(del);
+        }
+        element.appendChild(li);
+    });
+}
+
+function renderExamples() {
+    const key = "stamd.examples." + currentStore();
+    const saved = loadList(key);
+    renderQueryList($("examples"), saved.concat(DEFAULT_EXAMPLES), (i) => {
+        if (i < saved.length) {
+            saved.splice(i, 1);
+            saveList(key, saved);
+            renderExamples();
+        }
+    });
+}
+
+function renderHistory() {
+    renderQueryList($("history"), loadList("stamd.history." + currentStore()));
+}
+
+function addToHistory(query) {
+    const key = "stamd.history." + currentStore();
+    const history = loadList(key).filter((q) => q !== query);
+    history.unshift(query);
+    saveList(key, history.slice(0, HISTORY_SIZE));
+    renderHistory();
+}
+
+async function loadStore() {
+    const store = currentStore();
+    $("metadata").innerHTML = "";
+    $("stats").innerHTML = "";
+    $("resources").innerHTML = "";
+    renderExamples();
+    renderHistory();
+    if (!store) return;
+    try {
+        const metadata = await fetch(storeUrl(store) + "/metadata", { headers: { "Accept": "application/json" } });
+        if (metadata.ok) {
+            for (const [name, value] of Object.entries(await metadata.json())) {
+                const text = Array.isArray(value) ? value.join(", ") : String(value);
+                $("metadata").insertAdjacentHTML("beforeend", `<dt>${escapeHtml(name)}</dt><dd>${escapeHtml(text)}</dd>`);
+            }
+        }
+        const response = await fetch(storeUrl(store) + "/stats", { headers: { "Accept": "application/json" } });
+        if (response.ok) {
+            const stats = await response.json();
+            for (const [name, value] of Object.entries(stats)) {
+                $("stats").insertAdjacentHTML("beforeend", `<dt>${escapeHtml(name)}</dt><dd>${escapeHtml(String(value))}</dd>`);
+            }
+        }
+        const resources = await fetch(storeUrl(store) + "/resources", { headers: { "Accept": "application/json" } });
+        if (resources.ok) {
+            for (const id of await resources.json()) {
+                const li = document.createElement("li");
+                const a = document.createElement("a");
+                a.href = storeUrl(store) + "/resources/" + encodeURIComponent(id);
+                a.target = "_blank";
+                a.textContent = id;
+                li.appendChild(a);
+                $("resources").appendChild(li);
+            }
+        }
+    } catch (e) {
+        $("error").textContent = "Unable to load store information: " + e;
+    }
+}
+
+function showTab(name) {
+    document.querySelectorAll("#tabs button, #results > *").forEach((e) => {
+        e.classList.toggle("active", e.dataset.tab === name);
+    });
+}
+
+function cellValue(value) {
+    if (value === null || typeof value !== "object") {
+        return String(value);
+    } else if (value["@type"] === "TextSelection" || (value.text !== undefined && value.begin !== undefined)) {
+        return value.text;
+    } else if (value["@id"] !== undefined) {
+        return value["@id"];
+    } else {
+        return JSON.stringify(value);
+    }
+}
+
+function renderTable(results) {
+    if (!Array.isArray(results) || results.length === 0) {
+        $("result-table").innerHTML = "<p>No results</p>";
+        return;
+    }
+    // results are either a list of items (if a variable is selected) or a list of maps (variable => item)
+    const rows = results.map((row) => (row !== null && typeof row === "object" && row["@type"] === undefined && row.text === undefined) ? row : { "result": row });
+    const columns = [];
+    rows.forEach((row) => Object.keys(row).forEach((column) => {
+        if (!columns.includes(column)) columns.push(column);
+    }));
+    let html = "<table><tr><th>#</th>" + columns.map((c) => `<th>?${escapeHtml(c)}</th>`).join("") + "</tr>";
+    rows.forEach((row, i) => {
+        html += `<tr><td>${i + 1}</td>` + columns.map((c) => `<td>${row[c] !== undefined ? escapeHtml(cellValue(row[c])) : ""}</td>`).join("") + "</tr>";
+    });
+    html += "</table>";
+    $("result-table").innerHTML = html;
+}
+
+async function runQuery(accept) {
+    const form = new URLSearchParams();
+    form.append("store", currentStore());
+    form.append("query", $("query").value);
+    if ($("use").value.trim()) {
+        form.append("use", $("use").value.trim().replace(/^\?/, ""));
+    }
+    const response = await fetch("/query", {
+        method: "POST",
+        headers: { "Accept": accept, "Content-Type": "application/x-www-form-urlencoded" },
+        body: form,
+    });
+    if (!response.ok) {
+        const error = await response.json().catch(() => ({ message: response.statusText }));
+        throw new Error(error.name ? `${error.name}: ${error.message}` : error.message);
+    }
+    return response;
+}
+
+async function run() {
+    const query = $("query").value.trim();
+    if (!query || !currentStore()) return;
+    $("error").textContent = "";
+    $("run").disabled = true;
+    addToHistory(query);
+    try {
+        const results = await (await runQuery("application/json")).json();
+        $("result-json").textContent = JSON.stringify(results, null, 2);
+        renderTable(results);
+        // only read-only queries are visualised, re-running a query that edits the store would apply the edit twice
+        if (/^\s*(@\S+\s+)*SELECT\b/.test(query)) {
+            $("result-html").srcdoc = await (await runQuery("text/html")).text();
+        } else {
+            $("result-html").srcdoc = "<p>No visualisation available for this query</p>";
+        }
+    } catch (e) {
+        $("error").textContent = e.message;
+    } finally {
+        $("run").disabled = false;
+    }
+}
+
+function init() {
+    for (const store of STORES) {
+        const option = document.createElement("option");
+        option.value = store;
+        option.textContent = store;
+        $("store").appendChild(option);
+    }
+    const params = new URLSearchParams(window.location.search);
+    if (params.get("store")) $("store").value = params.get("store");
+    $("store").addEventListener("change", loadStore);
+    $("query").addEventListener("input", () => {
+        highlight($("query").value);
+        lint();
+    });
+    $("query").addEventListener("scroll", () => {
+        $("highlight").scrollTop = $("query").scrollTop;
+        $("highlight").scrollLeft = $("query").scrollLeft;
+    });
+    $("query").addEventListener("keydown", (e) => {
+        if (e.key === "Enter" && (e.ctrlKey || e.metaKey)) {
+            e.preventDefault();
+            run();
+        }
+    });
+    $("run").addEventListener("click", run);
+    $("save-example").addEventListener("click", () => {
+        const query = $("query").value.trim();
+        if (!query) return;
+        const key = "stamd.examples." + currentStore();
+        saveList(key, [query].concat(loadList(key).filter((q) => q !== query)));
+        renderExamples();
+    });
+    $("clear-history").addEventListener("click", () => {
+        localStorage.removeItem("stamd.history." + currentStore());
+        renderHistory();
+    });
+    document.querySelectorAll("#tabs button").forEach((button) => {
+        button.addEventListener("click", () => showTab(button.dataset.tab));
+    });
+    setQuery(params.get("query") || "");
+    loadStore();
+}
+
+init();
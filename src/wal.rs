@@ -0,0 +1,237 @@
+use crate::backup::Backups;
+use crate::common::{parse_query, ApiError};
+use crate::history::{Change, History};
+use crate::merge::MergePlan;
+use serde::{Deserialize, Serialize};
+use stam::{AnnotationStore, AssociatedFile, Text, TextResourceBuilder};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{info, warn};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// An entry in the write-ahead log, holding everything needed to replay a change
+enum Entry {
+    /// The first entry, identifies the version of the store file the log applies to
+    Start {
+        /// Modification time of the store file (in nanoseconds since the unix epoch) when the log was started, if it existed
+        modified: Option<u64>,
+    },
+    Query {
+        queries: Vec<String>,
+    },
+    Resource {
+        id: String,
+        filename: Option<String>,
+        text: String,
+    },
+    Revert {
+        revision: usize,
+    },
+    Restore {
+        backup: u64,
+    },
+    Merge {
+        plan: MergePlan,
+    },
+}
+
+/// The write-ahead log of a single store. Every change is appended (and flushed to disk) before it is acknowledged,
+/// so changes that have not been saved yet survive a crash. The log is replayed when the store is loaded and
+/// emptied once the store is saved. All changes must be appended while holding the store's write lock.
+pub struct Journal {
+    filename: PathBuf,
+    /// The store file the log applies to
+    storefile: PathBuf,
+}
+
+impl Journal {
+    pub fn new(filename: impl Into<PathBuf>, storefile: impl Into<PathBuf>) -> Self {
+        Self {
+            filename: filename.into(),
+            storefile: storefile.into(),
+        }
+    }
+
+    /// Appends a change that was just applied to the store
+    pub fn append(&self, store: &AnnotationStore, change: &Change) -> Result<(), ApiError> {
+        let entry = match change {
+            Change::Initial => return Ok(()),
+            Change::Query { queries } => Entry::Query {
+                queries: queries.clone(),
+            },
+            Change::Resource { id } => {
+                let resource = store
+                    .resource(id.as_str())
+                    .ok_or(ApiError::InternalError("Resource must exist"))?;
+                Entry::Resource {
+                    id: id.clone(),
+                    filename: resource.as_ref().filename().map(|s| s.to_string()),
+                    text: resource.text().to_string(),
+                }
+            }
+            Change::Revert { revision } => Entry::Revert {
+                revision: *revision,
+            },
+            Change::Restore { backup } => Entry::Restore { backup: *backup },
+            Change::Merge { plan, .. } => Entry::Merge {
+                plan: plan.as_ref().clone(),
+            },
+        };
+        let mut data = String::new();
+        if self.is_empty() {
+            data += &to_line(&Entry::Start {
+                modified: modified(&self.storefile),
+            })?;
+        }
+        data += &to_line(&entry)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.filename)
+            .and_then(|mut file| {
+                file.write_all(data.as_bytes())?;
+                file.sync_data()
+            })
+            .map_err(|_| ApiError::InternalError("Unable to write to write-ahead log"))
+    }
+
+    /// Replays all logged changes on a store that was just loaded from file, returns the number of replayed changes.
+    /// A log that belongs to an older version of the store file (i.e. the store was saved but the log was not emptied) is discarded.
+    pub fn replay(
+        &self,
+        store: &mut AnnotationStore,
+        history: Option<&History>,
+        backups: &Backups,
+    ) -> Result<usize, ApiError> {
+        if !self.filename.exists() {
+            return Ok(0);
+        }
+        let data = std::fs::read(&self.filename)
+            .map_err(|_| ApiError::InternalError("Unable to read write-ahead log"))?;
+        let mut count = 0;
+        let mut valid = 0; //length of the complete entries
+        for (i, line) in data.split_inclusive(|c| *c == b'\n').enumerate() {
+            let entry: Entry = match line.strip_suffix(b"\n") {
+                Some(line) => serde_json::from_slice(line)
+                    .map_err(|_| ApiError::InternalError("Invalid write-ahead log"))?,
+                None => {
+                    //an incomplete last entry was never acknowledged, so it can be dropped
+                    warn!("Dropping incomplete entry in {}", self.filename.display());
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&self.filename)
+                        .and_then(|file| file.set_len(valid as u64))
+                        .map_err(|_| ApiError::InternalError("Unable to repair write-ahead log"))?;
+                    break;
+                }
+            };
+            valid += line.len();
+            match entry {
+                Entry::Start { modified: start } if i == 0 => {
+                    if start != modified(&self.storefile) {
+                        warn!(
+                            "Discarding write-ahead log {}, the store was saved after it was started",
+                            self.filename.display()
+                        );
+                        self.truncate()?;
+                        return Ok(0);
+                    }
+                }
+                _ if i == 0 => {
+                    return Err(ApiError::InternalError("Invalid write-ahead log"));
+                }
+                Entry::Start { .. } => {
+                    return Err(ApiError::InternalError("Invalid write-ahead log"));
+                }
+                Entry::Query { queries } => {
+                    for querystring in queries.iter() {
+                        let (query, _) = parse_query(querystring)?;
+                        //mutations are applied immediately, the results are not needed
+                        store.query_mut(query)?.for_each(drop);
+                    }
+                    count += 1;
+                }
+                Entry::Resource { id, filename, text } => {
+                    let mut builder = TextResourceBuilder::new().with_id(id).with_text(text);
+                    if let Some(filename) = filename {
+                        builder = builder.with_filename(filename);
+                    }
+                    store.add_resource(builder)?;
+                    count += 1;
+                }
+                Entry::Revert { revision } => {
+                    let history = history.ok_or(ApiError::InternalError(
+                        "Write-ahead log contains a revert but history is not enabled",
+                    ))?;
+                    *store = history.state(revision)?;
+                    count += 1;
+                }
+                Entry::Restore { backup } => {
+                    *store = backups.load(backup, store)?;
+                    count += 1;
+                }
+                Entry::Merge { plan } => {
+                    plan.apply(store)?;
+                    count += 1;
+                }
+            }
+        }
+        if count > 0 {
+            info!(
+                "Replayed {} change(s) from {}",
+                count,
+                self.filename.display()
+            );
+        }
+        Ok(count)
+    }
+
+    fn is_empty(&self) -> bool {
+        std::fs::metadata(&self.filename).map_or(true, |metadata| metadata.len() == 0)
+    }
+
+    /// Returns the current end of the log, to return to with [`Self::rollback()`]
+    pub fn position(&self) -> u64 {
+        std::fs::metadata(&self.filename).map_or(0, |metadata| metadata.len())
+    }
+
+    /// Removes everything that was appended after the given position, for changes that were undone
+    pub fn rollback(&self, position: u64) -> Result<(), ApiError> {
+        if self.position() > position {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.filename)
+                .and_then(|file| file.set_len(position))
+                .map_err(|_| ApiError::InternalError("Unable to roll back write-ahead log"))?;
+        }
+        Ok(())
+    }
+
+    /// Empties the log, must be called after the store was saved successfully
+    pub fn truncate(&self) -> Result<(), ApiError> {
+        if self.filename.exists() {
+            std::fs::remove_file(&self.filename)
+                .map_err(|_| ApiError::InternalError("Unable to remove write-ahead log"))?;
+        }
+        Ok(())
+    }
+}
+
+fn to_line(entry: &Entry) -> Result<String, ApiError> {
+    let mut line = serde_json::to_string(entry)
+        .map_err(|_| ApiError::InternalError("Unable to serialize write-ahead log"))?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Returns the modification time of a file in nanoseconds since the unix epoch
+pub fn modified(filename: &Path) -> Option<u64> {
+    std::fs::metadata(filename)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .and_then(|duration| u64::try_from(duration.as_nanos()).ok())
+}